@@ -0,0 +1,204 @@
+mod oauth;
+mod sasl;
+mod scram;
+
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+
+pub use oauth::{OAUTHBEARER_ERROR, XOAUTH2_ERROR};
+pub use sasl::Sasl;
+pub use scram::{ScramCredentials, ScramExchange};
+
+/**
+## Authenticator trait
+   The `Authenticator` trait validates the credentials sent by clients with the AUTH command.
+   It is called once the SASL exchange is complete and should return `true` if the
+   credentials are valid.
+
+   The trait is implemented with the `async_trait` attribute:
+   ```rust
+   use async_trait::async_trait;
+   use minismtp::auth::Authenticator;
+
+   #[derive(Debug)]
+   struct SingleUser;
+
+   #[async_trait]
+   impl Authenticator for SingleUser {
+       async fn validate(&self, username: &str, password: &str) -> bool {
+           username == "user" && password == "secret"
+       }
+   }
+   ```
+*/
+#[async_trait]
+pub trait Authenticator: Debug + Send + Sync {
+    async fn validate(&self, username: &str, password: &str) -> bool;
+}
+
+/**
+## CredentialStore trait
+   The `CredentialStore` trait provides the stored credentials of users for the
+   challenge-response mechanisms, with which the password never crosses the wire.
+   - `scram_credentials` returns the salted keys used by SCRAM-SHA-256, which can be
+     derived from a password with `ScramCredentials::new`.
+   - `password` returns the password used by CRAM-MD5, which requires the server to know it.
+     CRAM-MD5 is only offered if `provides_passwords` is implemented to return `true`.
+
+   ```rust
+   use async_trait::async_trait;
+   use minismtp::auth::{CredentialStore, ScramCredentials};
+
+   #[derive(Debug)]
+   struct SingleUser;
+
+   #[async_trait]
+   impl CredentialStore for SingleUser {
+       async fn scram_credentials(&self, username: &str) -> Option<ScramCredentials> {
+           (username == "user").then(|| ScramCredentials::new("secret", b"salt", 4096).unwrap())
+       }
+   }
+   ```
+*/
+#[async_trait]
+pub trait CredentialStore: Debug + Send + Sync {
+    async fn scram_credentials(&self, username: &str) -> Option<ScramCredentials>;
+
+    async fn password(&self, _username: &str) -> Option<String> {
+        None
+    }
+
+    fn provides_passwords(&self) -> bool {
+        false
+    }
+}
+
+/**
+## TokenVerifier trait
+   The `TokenVerifier` trait verifies the bearer tokens sent with the XOAUTH2 and OAUTHBEARER
+   mechanisms, e.g. by checking the signature and claims of a JWT.
+   It receives the username sent by the client, if any, and the token, and returns the
+   identity the client authenticated as, or `None` if the token is rejected.
+
+   ```rust
+   use async_trait::async_trait;
+   use minismtp::auth::TokenVerifier;
+
+   #[derive(Debug)]
+   struct StaticToken;
+
+   #[async_trait]
+   impl TokenVerifier for StaticToken {
+       async fn verify(&self, username: Option<&str>, token: &str) -> Option<String> {
+           (token == "secret-token").then(|| username.unwrap_or("service").to_string())
+       }
+   }
+   ```
+*/
+#[async_trait]
+pub trait TokenVerifier: Debug + Send + Sync {
+    async fn verify(&self, username: Option<&str>, token: &str) -> Option<String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/**
+## Validator enum
+   The `Validator` enum represents what a mechanism is validated with.
+   It includes the following variants:
+   - `Authenticator`: A username and password checked by the `Authenticator`.
+   - `CredentialStore`: A challenge-response checked against the `CredentialStore`.
+   - `TokenVerifier`: A bearer token checked by the `TokenVerifier`.
+*/
+pub enum Validator {
+    Authenticator,
+    CredentialStore,
+    TokenVerifier,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/**
+## Mechanism enum
+   The `Mechanism` enum represents the SASL mechanisms supported by the AUTH command.
+   It includes the following variants:
+   - `ScramSha256`: The SCRAM-SHA-256 mechanism (RFC 7677), validated with a credential store.
+   - `CramMd5`: The CRAM-MD5 mechanism (RFC 2195), validated with a credential store.
+   - `Plain`: The PLAIN mechanism (RFC 4616), only offered over TLS.
+   - `Login`: The LOGIN mechanism, only offered over TLS.
+   - `OAuthBearer`: The OAUTHBEARER mechanism (RFC 7628), only offered over TLS.
+   - `XOAuth2`: The XOAUTH2 mechanism, only offered over TLS.
+*/
+pub enum Mechanism {
+    ScramSha256,
+    CramMd5,
+    Plain,
+    Login,
+    OAuthBearer,
+    XOAuth2,
+}
+
+impl Mechanism {
+    /**
+       All supported mechanisms, in the order they are advertised.
+    */
+    pub const ALL: [Mechanism; 6] = [
+        Mechanism::ScramSha256,
+        Mechanism::CramMd5,
+        Mechanism::Plain,
+        Mechanism::Login,
+        Mechanism::OAuthBearer,
+        Mechanism::XOAuth2,
+    ];
+
+    /**
+       The name of the mechanism as used in the EHLO response and the AUTH command.
+    */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mechanism::ScramSha256 => "SCRAM-SHA-256",
+            Mechanism::CramMd5 => "CRAM-MD5",
+            Mechanism::Plain => "PLAIN",
+            Mechanism::Login => "LOGIN",
+            Mechanism::OAuthBearer => "OAUTHBEARER",
+            Mechanism::XOAuth2 => "XOAUTH2",
+        }
+    }
+
+    /**
+       Whether the mechanism sends a password or token in clear text and therefore requires TLS.
+    */
+    pub fn requires_tls(&self) -> bool {
+        matches!(
+            self,
+            Mechanism::Plain | Mechanism::Login | Mechanism::OAuthBearer | Mechanism::XOAuth2
+        )
+    }
+
+    /**
+       What the mechanism is validated with, it is only offered if that validator is set.
+    */
+    pub fn validator(&self) -> Validator {
+        match self {
+            Mechanism::Plain | Mechanism::Login => Validator::Authenticator,
+            Mechanism::ScramSha256 | Mechanism::CramMd5 => Validator::CredentialStore,
+            Mechanism::OAuthBearer | Mechanism::XOAuth2 => Validator::TokenVerifier,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+/**
+## SASL step
+   The `SaslStep` enum represents the outcome of processing a client response.
+   It includes the following variants:
+   - `Challenge`: The exchange continues with the given challenge.
+   - `Success`: The client authenticated as the given identity.
+   - `Failure`: The credentials are invalid.
+   - `Malformed`: The client response could not be parsed.
+*/
+pub enum SaslStep {
+    Challenge(Vec<u8>),
+    Success(String),
+    Failure,
+    Malformed,
+}
@@ -0,0 +1,73 @@
+use super::scram::decode_name;
+
+/**
+   The error challenge sent when an OAUTHBEARER token is rejected (RFC 7628).
+*/
+pub const OAUTHBEARER_ERROR: &str = r#"{"status":"invalid_token","schemes":"bearer"}"#;
+
+/**
+   The error challenge sent when an XOAUTH2 token is rejected.
+*/
+pub const XOAUTH2_ERROR: &str = r#"{"status":"401","schemes":"bearer"}"#;
+
+/**
+   Parses an XOAUTH2 client response, e.g. `user=name^Aauth=Bearer token^A^A`,
+   into the username and the token.
+*/
+pub fn parse_xoauth2(message: &str) -> Option<(String, String)> {
+    let pairs = key_value_pairs(message)?;
+    let username = pairs
+        .iter()
+        .find_map(|(key, value)| (*key == "user").then_some(*value))?;
+    Some((username.to_string(), bearer_token(&pairs)?))
+}
+
+/**
+   Parses an OAUTHBEARER client response, e.g. `n,a=name,^Aauth=Bearer token^A^A`,
+   into the authorization identity from the GS2 header, if any, and the token.
+   Channel binding is not supported, so only the `n` and `y` flags are accepted.
+*/
+pub fn parse_oauthbearer(message: &str) -> Option<(Option<String>, String)> {
+    let (gs2_header, pairs) = message.split_once('\x01')?;
+    let mut gs2_header = gs2_header.split(',');
+    let (Some("n" | "y"), Some(authzid), Some(""), None) = (
+        gs2_header.next(),
+        gs2_header.next(),
+        gs2_header.next(),
+        gs2_header.next(),
+    ) else {
+        return None;
+    };
+    let authzid = match authzid {
+        "" => None,
+        _ => Some(decode_name(authzid.strip_prefix("a=")?)?),
+    };
+
+    let pairs = key_value_pairs(pairs)?;
+    Some((authzid, bearer_token(&pairs)?))
+}
+
+/**
+   Splits the `key=value` pairs separated by `^A` and terminated by `^A^A`.
+*/
+fn key_value_pairs(message: &str) -> Option<Vec<(&str, &str)>> {
+    message
+        .strip_suffix("\x01\x01")?
+        .split('\x01')
+        .map(|pair| pair.split_once('='))
+        .collect()
+}
+
+/**
+   Extracts the token from the `auth` pair, e.g. `auth=Bearer token`.
+*/
+fn bearer_token(pairs: &[(&str, &str)]) -> Option<String> {
+    let auth = pairs
+        .iter()
+        .find_map(|(key, value)| (*key == "auth").then_some(*value))?;
+    let (scheme, token) = auth.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") || token.is_empty() {
+        return None;
+    }
+    Some(token.to_string())
+}
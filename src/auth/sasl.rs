@@ -0,0 +1,223 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::{hash::MessageDigest, memcmp, rand::rand_bytes};
+
+use super::{
+    oauth::{parse_oauthbearer, parse_xoauth2, OAUTHBEARER_ERROR, XOAUTH2_ERROR},
+    scram::{hmac, parse_client_first},
+    Authenticator, CredentialStore, Mechanism, SaslStep, ScramCredentials, ScramExchange,
+    TokenVerifier,
+};
+use crate::parser::responses::DOMAIN;
+
+#[derive(Debug, Clone, PartialEq)]
+/**
+## SASL exchange
+   The `Sasl` enum represents the progress of a SASL exchange started by the AUTH command.
+   It includes the following variants:
+   - `Plain`: Awaiting the PLAIN credentials.
+   - `LoginUsername`: Awaiting the LOGIN username.
+   - `LoginPassword`: Awaiting the LOGIN password for the given username.
+   - `CramMd5`: Awaiting the CRAM-MD5 digest of the given challenge.
+   - `ScramFirst`: Awaiting the first SCRAM client message.
+   - `ScramFinal`: Awaiting the final SCRAM client message.
+   - `ScramVerified`: Awaiting the client acknowledgement of the server signature.
+   - `OAuthBearer`: Awaiting the OAUTHBEARER token.
+   - `XOAuth2`: Awaiting the XOAUTH2 token.
+   - `OAuthFailed`: Awaiting the client acknowledgement of the error challenge for a rejected token.
+*/
+pub enum Sasl {
+    Plain,
+    LoginUsername,
+    LoginPassword(String),
+    CramMd5(String),
+    ScramFirst,
+    ScramFinal(Box<ScramExchange>),
+    ScramVerified(String),
+    OAuthBearer,
+    XOAuth2,
+    OAuthFailed,
+}
+
+impl Sasl {
+    /**
+       Starts a new exchange for the given mechanism.
+    */
+    pub fn new(mechanism: Mechanism) -> Self {
+        match mechanism {
+            Mechanism::ScramSha256 => Sasl::ScramFirst,
+            Mechanism::CramMd5 => Sasl::CramMd5(cram_md5_challenge()),
+            Mechanism::Plain => Sasl::Plain,
+            Mechanism::Login => Sasl::LoginUsername,
+            Mechanism::OAuthBearer => Sasl::OAuthBearer,
+            Mechanism::XOAuth2 => Sasl::XOAuth2,
+        }
+    }
+
+    /**
+       The challenge sent when the client did not send an initial response with the AUTH command.
+    */
+    pub fn initial_challenge(&self) -> Vec<u8> {
+        match self {
+            Sasl::LoginUsername => b"Username:".to_vec(),
+            Sasl::CramMd5(challenge) => challenge.as_bytes().to_vec(),
+            _ => Vec::new(),
+        }
+    }
+
+    /**
+       Processes a decoded client response and advances the exchange.
+       PLAIN and LOGIN are validated with the authenticator, CRAM-MD5 and SCRAM-SHA-256
+       with the credential store, OAUTHBEARER and XOAUTH2 with the token verifier.
+    */
+    pub async fn step(
+        &mut self,
+        response: &[u8],
+        authenticator: Option<&dyn Authenticator>,
+        credential_store: Option<&dyn CredentialStore>,
+        token_verifier: Option<&dyn TokenVerifier>,
+    ) -> SaslStep {
+        let Ok(response) = std::str::from_utf8(response) else {
+            return SaslStep::Malformed;
+        };
+        match self {
+            Sasl::Plain => {
+                // The response is made of the authorization identity, the username and the
+                // password, separated by NUL characters (RFC 4616)
+                let mut parts = response.split('\0');
+                let (Some(authzid), Some(username), Some(password), None) =
+                    (parts.next(), parts.next(), parts.next(), parts.next())
+                else {
+                    return SaslStep::Malformed;
+                };
+
+                // Acting on behalf of another identity is not supported
+                if !authzid.is_empty() && authzid != username {
+                    return SaslStep::Failure;
+                }
+                validate(authenticator, username, password).await
+            }
+            Sasl::LoginUsername => {
+                *self = Sasl::LoginPassword(response.to_string());
+                SaslStep::Challenge(b"Password:".to_vec())
+            }
+            Sasl::LoginPassword(username) => validate(authenticator, username, response).await,
+            Sasl::CramMd5(challenge) => {
+                // The response is the username and the hex HMAC-MD5 of the challenge (RFC 2195)
+                let Some((username, digest)) = response.rsplit_once(' ') else {
+                    return SaslStep::Malformed;
+                };
+                let Some(store) = credential_store else {
+                    return SaslStep::Failure;
+                };
+                let Some(password) = store.password(username).await else {
+                    return SaslStep::Failure;
+                };
+                let Ok(expected) = hmac(
+                    MessageDigest::md5(),
+                    password.as_bytes(),
+                    challenge.as_bytes(),
+                ) else {
+                    return SaslStep::Failure;
+                };
+                let expected = expected
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                if expected.len() == digest.len()
+                    && memcmp::eq(expected.as_bytes(), digest.to_lowercase().as_bytes())
+                {
+                    SaslStep::Success(username.to_string())
+                } else {
+                    SaslStep::Failure
+                }
+            }
+            Sasl::ScramFirst => {
+                let Some((gs2_header, bare, username, nonce)) = parse_client_first(response) else {
+                    return SaslStep::Malformed;
+                };
+                let Some(store) = credential_store else {
+                    return SaslStep::Failure;
+                };
+                let credentials = match store.scram_credentials(&username).await {
+                    Some(credentials) => credentials,
+                    None => ScramCredentials::unknown(&username),
+                };
+                match ScramExchange::new(gs2_header, bare, username, nonce, credentials) {
+                    Ok(exchange) => {
+                        let challenge = exchange.server_first().as_bytes().to_vec();
+                        *self = Sasl::ScramFinal(Box::new(exchange));
+                        SaslStep::Challenge(challenge)
+                    }
+                    Err(e) => {
+                        log::error!("Error starting SCRAM exchange: {}", e);
+                        SaslStep::Failure
+                    }
+                }
+            }
+            Sasl::ScramFinal(exchange) => match exchange.verify(response) {
+                // The server proves that it knows the credentials too
+                Some(server_final) => {
+                    *self = Sasl::ScramVerified(exchange.username.clone());
+                    SaslStep::Challenge(server_final.into_bytes())
+                }
+                None => SaslStep::Failure,
+            },
+            Sasl::ScramVerified(username) => SaslStep::Success(username.clone()),
+            Sasl::OAuthBearer | Sasl::XOAuth2 => {
+                let parsed = match self {
+                    Sasl::XOAuth2 => parse_xoauth2(response)
+                        .map(|(username, token)| (Some(username), token, XOAUTH2_ERROR)),
+                    _ => parse_oauthbearer(response)
+                        .map(|(username, token)| (username, token, OAUTHBEARER_ERROR)),
+                };
+                let Some((username, token, error)) = parsed else {
+                    return SaslStep::Malformed;
+                };
+                let Some(verifier) = token_verifier else {
+                    return SaslStep::Failure;
+                };
+                match verifier.verify(username.as_deref(), &token).await {
+                    Some(identity) => SaslStep::Success(identity),
+                    // The client acknowledges the error before the exchange fails (RFC 7628)
+                    None => {
+                        *self = Sasl::OAuthFailed;
+                        SaslStep::Challenge(error.as_bytes().to_vec())
+                    }
+                }
+            }
+            Sasl::OAuthFailed => SaslStep::Failure,
+        }
+    }
+}
+
+/**
+   Builds a unique CRAM-MD5 challenge, e.g. `<1896.697170952@minismtp>`.
+*/
+fn cram_md5_challenge() -> String {
+    let mut random = [0; 4];
+    let _ = rand_bytes(&mut random);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    format!("<{}.{}@{}>", u32::from_be_bytes(random), timestamp, *DOMAIN)
+}
+
+/**
+   Checks a username and password with the authenticator.
+*/
+async fn validate(
+    authenticator: Option<&dyn Authenticator>,
+    username: &str,
+    password: &str,
+) -> SaslStep {
+    let Some(authenticator) = authenticator else {
+        return SaslStep::Failure;
+    };
+    if authenticator.validate(username, password).await {
+        SaslStep::Success(username.to_string())
+    } else {
+        SaslStep::Failure
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::LazyLock;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use openssl::{
+    error::ErrorStack, hash::MessageDigest, memcmp, pkcs5::pbkdf2_hmac, pkey::PKey,
+    rand::rand_bytes, sha::sha256, sign::Signer,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/**
+## SCRAM credentials
+   The `ScramCredentials` struct holds the salted keys of a user for SCRAM-SHA-256 (RFC 7677),
+   so that the password itself never has to be stored by the server.
+   It includes the following fields:
+   - `salt`: The salt used to derive the keys.
+   - `iterations`: The number of PBKDF2 iterations used to derive the keys.
+   - `stored_key`: `H(HMAC(SaltedPassword, "Client Key"))`, used to verify the client proof.
+   - `server_key`: `HMAC(SaltedPassword, "Server Key")`, used to sign the server reply.
+*/
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    /**
+       Derives the credentials from a password, e.g. when a user is created or changes password.
+    */
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> Result<Self, ErrorStack> {
+        let mut salted_password = [0; 32];
+        pbkdf2_hmac(
+            password.as_bytes(),
+            salt,
+            iterations as usize,
+            MessageDigest::sha256(),
+            &mut salted_password,
+        )?;
+        let client_key = hmac(MessageDigest::sha256(), &salted_password, b"Client Key")?;
+        Ok(ScramCredentials {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: sha256(&client_key).to_vec(),
+            server_key: hmac(MessageDigest::sha256(), &salted_password, b"Server Key")?,
+        })
+    }
+
+    /**
+       Stands in for the credentials of a user that does not exist, so that the exchange only
+       fails at the final step and does not reveal which users exist. The salt is derived from
+       the username with a secret of the process, so that it is the same on every attempt.
+    */
+    pub(crate) fn unknown(username: &str) -> Self {
+        static SECRET: LazyLock<[u8; 32]> = LazyLock::new(|| {
+            let mut secret = [0; 32];
+            let _ = rand_bytes(&mut secret);
+            secret
+        });
+        let salt = hmac(MessageDigest::sha256(), &*SECRET, username.as_bytes())
+            .map(|mac| mac[..16].to_vec())
+            .unwrap_or_default();
+        ScramCredentials {
+            salt,
+            // The iteration count recommended by RFC 7677
+            iterations: 4096,
+            // No client key hashes to these keys, so the proof is always rejected
+            stored_key: vec![0; 32],
+            server_key: vec![0; 32],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/**
+## SCRAM exchange
+   The `ScramExchange` struct holds what the server needs to verify the final client message.
+   It includes the following fields:
+   - `username`: The user the client authenticates as.
+   - `gs2_header`: The GS2 header of the first client message, echoed in the final message.
+   - `client_first_bare`: The first client message without the GS2 header.
+   - `server_first`: The first server message.
+   - `nonce`: The combined client and server nonce.
+   - `credentials`: The credentials of the user.
+*/
+pub struct ScramExchange {
+    pub username: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    credentials: ScramCredentials,
+}
+
+/**
+   Parses the first client message, e.g. `n,,n=user,r=nonce`, into the GS2 header,
+   the bare message, the username and the client nonce.
+   Channel binding is not supported, so only the `n` and `y` flags are accepted.
+*/
+pub fn parse_client_first(message: &str) -> Option<(&str, &str, String, &str)> {
+    let (flag, rest) = message.split_once(',')?;
+    let (authzid, bare) = rest.split_once(',')?;
+    if !matches!(flag, "n" | "y") || !(authzid.is_empty() || authzid.starts_with("a=")) {
+        return None;
+    }
+    let gs2_header = &message[..flag.len() + authzid.len() + 2];
+
+    let mut attributes = bare.split(',');
+    let username = decode_name(attributes.next()?.strip_prefix("n=")?)?;
+    let nonce = attributes.next()?.strip_prefix("r=")?;
+    // Acting on behalf of another identity is not supported
+    if authzid.len() > 2 && decode_name(&authzid[2..])? != username {
+        return None;
+    }
+    if nonce.is_empty() || nonce.contains(',') {
+        return None;
+    }
+    Some((gs2_header, bare, username, nonce))
+}
+
+impl ScramExchange {
+    /**
+       Starts the exchange after the first client message with the credentials of the user.
+    */
+    pub fn new(
+        gs2_header: &str,
+        client_first_bare: &str,
+        username: String,
+        client_nonce: &str,
+        credentials: ScramCredentials,
+    ) -> Result<Self, ErrorStack> {
+        let mut server_nonce = [0; 18];
+        rand_bytes(&mut server_nonce)?;
+        let nonce = format!("{}{}", client_nonce, STANDARD.encode(server_nonce));
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            STANDARD.encode(&credentials.salt),
+            credentials.iterations
+        );
+        Ok(ScramExchange {
+            username,
+            gs2_header: gs2_header.to_string(),
+            client_first_bare: client_first_bare.to_string(),
+            server_first,
+            nonce,
+            credentials,
+        })
+    }
+
+    /**
+       The first server message, containing the nonce, the salt and the iteration count.
+    */
+    pub fn server_first(&self) -> &str {
+        &self.server_first
+    }
+
+    /**
+       Verifies the final client message, e.g. `c=biws,r=nonce,p=proof`, and returns the
+       final server message containing the server signature if the proof is valid.
+    */
+    pub fn verify(&self, message: &str) -> Option<String> {
+        let (without_proof, proof) = message.rsplit_once(",p=")?;
+        let mut attributes = without_proof.split(',');
+        let channel_binding = STANDARD
+            .decode(attributes.next()?.strip_prefix("c=")?)
+            .ok()?;
+        let nonce = attributes.next()?.strip_prefix("r=")?;
+        if channel_binding != self.gs2_header.as_bytes() || nonce != self.nonce {
+            return None;
+        }
+        let proof = STANDARD.decode(proof).ok()?;
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+        let client_signature = hmac(
+            MessageDigest::sha256(),
+            &self.credentials.stored_key,
+            auth_message.as_bytes(),
+        )
+        .ok()?;
+        if proof.len() != client_signature.len() {
+            return None;
+        }
+        // The client key is recovered from the proof and must hash to the stored key
+        let client_key = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+        if !memcmp::eq(&sha256(&client_key), &self.credentials.stored_key) {
+            return None;
+        }
+
+        let server_signature = hmac(
+            MessageDigest::sha256(),
+            &self.credentials.server_key,
+            auth_message.as_bytes(),
+        )
+        .ok()?;
+        Some(format!("v={}", STANDARD.encode(server_signature)))
+    }
+}
+
+/**
+   Computes the HMAC of the data with the given digest.
+*/
+pub(crate) fn hmac(digest: MessageDigest, key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(digest, &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+/**
+   Decodes a username in which `,` and `=` are escaped as `=2C` and `=3D`.
+*/
+pub(super) fn decode_name(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut parts = name.split('=');
+    decoded.push_str(parts.next()?);
+    for part in parts {
+        match part.get(..2)? {
+            "2C" => decoded.push(','),
+            "3D" => decoded.push('='),
+            _ => return None,
+        }
+        decoded.push_str(&part[2..]);
+    }
+    Some(decoded)
+}
@@ -0,0 +1,96 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use super::{Connection, State, Stream};
+use crate::{
+    auth::{Mechanism, SaslStep, Validator},
+    parser::{
+        responses::{AUTH_CANCELLED, AUTH_FAILED, AUTH_SUCCESSFUL, INVALID_ARGUMENTS},
+        Response,
+    },
+};
+
+impl Connection {
+    /**
+       The SASL mechanisms that can be used on this connection.
+       Mechanisms are only offered when they can be validated, with the authenticator,
+       the credential store or the token verifier, and mechanisms sending the password in clear text are only
+       offered once the connection is encrypted. CRAM-MD5 is only offered if the credential store
+       provides passwords.
+    */
+    pub fn mechanisms(&self) -> Vec<Mechanism> {
+        let encrypted = matches!(self.stream, Stream::Encrypted(_));
+        Mechanism::ALL
+            .into_iter()
+            .filter(|mechanism| match mechanism.validator() {
+                Validator::Authenticator => self.authenticator.is_some(),
+                Validator::CredentialStore => match &self.credential_store {
+                    Some(store) => *mechanism != Mechanism::CramMd5 || store.provides_passwords(),
+                    None => false,
+                },
+                Validator::TokenVerifier => self.token_verifier.is_some(),
+            })
+            .filter(|mechanism| encrypted || !mechanism.requires_tls())
+            .collect()
+    }
+
+    /// Processes a base64 encoded client response during a SASL exchange (RFC 4954)
+    pub async fn authenticate(&mut self, response: &[u8]) -> Response {
+        let State::Auth(domain, mut sasl) = std::mem::replace(&mut self.state, State::Initial)
+        else {
+            return INVALID_ARGUMENTS.into();
+        };
+        // Unless the exchange continues, the client returns to the state after EHLO
+        self.state = State::Ehlo(domain.clone());
+
+        let response = response.strip_suffix(b"\r\n").unwrap_or(response);
+        // The client cancels the exchange with a single asterisk
+        if response == b"*" {
+            log::info!("Authentication cancelled");
+            return AUTH_CANCELLED.into();
+        }
+        // An empty initial response is sent as a single equals sign
+        let decoded = match response {
+            b"=" => Vec::new(),
+            _ => match STANDARD.decode(response) {
+                Ok(decoded) => decoded,
+                Err(_) => {
+                    log::error!("Invalid base64 in authentication response");
+                    return INVALID_ARGUMENTS.into();
+                }
+            },
+        };
+
+        let authenticator = self.authenticator.clone();
+        let credential_store = self.credential_store.clone();
+        let token_verifier = self.token_verifier.clone();
+        match sasl
+            .step(
+                &decoded,
+                authenticator.as_deref(),
+                credential_store.as_deref(),
+                token_verifier.as_deref(),
+            )
+            .await
+        {
+            SaslStep::Challenge(challenge) => {
+                self.state = State::Auth(domain, sasl);
+                format!("334 {}\r\n", STANDARD.encode(challenge))
+                    .into_bytes()
+                    .into()
+            }
+            SaslStep::Success(identity) => {
+                log::info!("Authenticated as {:?}", identity);
+                self.authenticated = Some(identity);
+                AUTH_SUCCESSFUL.into()
+            }
+            SaslStep::Failure => {
+                log::error!("Authentication failed");
+                AUTH_FAILED.into()
+            }
+            SaslStep::Malformed => {
+                log::error!("Malformed authentication response");
+                INVALID_ARGUMENTS.into()
+            }
+        }
+    }
+}
@@ -1,46 +1,74 @@
-use std::{path::PathBuf, time::Duration};
+use std::time::Duration;
 
-use super::{Connection, State, Stream, TlsConfig};
+use crate::server::Config;
+
+use super::{policy::in_networks, Connection, Session, State, Stream, TlsConfig};
 
 impl Connection {
     /**
        ## New method
        The `new` method creates a new `Connection` instance.
        It takes the following arguments:
-       - `domain`: The domain of the connection.
        - `stream`: The stream used for the connection.
-       - `cert_path`: An optional path to the certificate file.
-       - `key_path`: An optional path to the key file.
-       - `buffer_size`: An optional buffer size for reading incoming data.
-       - `timeout`: The duration after which the connection will timeout.
+       - `config`: The configuration of the server that accepted the connection, from which
+         the domain, TLS configuration, buffer size, timeout, maximum message size,
+         authentication and TLS settings and mail channel are taken.
 
        It returns a new `Connection` instance.
     */
-    pub async fn new(
-        domain: String,
-        stream: Stream,
-        cert_path: Option<PathBuf>,
-        key_path: Option<PathBuf>,
-        buffer_size: Option<usize>,
-        timeout: Duration,
-    ) -> Self {
+    pub async fn new(stream: Stream, config: &Config) -> Self {
         let state = State::Initial;
 
-        let tls_config = match (cert_path, key_path) {
-            (Some(cert_path), Some(key_path)) => TlsConfig::Encrypted {
-                cert_path,
-                key_path,
-            },
-            _ => TlsConfig::Plain,
+        let tls_config = match &config.tls_config {
+            Some(tls_config) => TlsConfig::Encrypted(tls_config.clone()),
+            None => TlsConfig::Plain,
         };
 
+        let mut session = Session::new(&stream);
+        session.trusted = session
+            .remote_addr
+            .is_some_and(|addr| in_networks(&config.trusted_networks, addr.ip()));
+        log::info!("Session {} from {:?}", session.id, session.remote_addr);
+
         Connection {
-            domain,
+            domain: config.domain.clone(),
             stream,
             state,
             tls_config,
-            buffer_size,
-            timeout,
+            buffer_size: config.buffer_size,
+            timeout: config.timeout.unwrap_or(Duration::from_secs(10)),
+            // This expression results in 10,485,760 bytes, which is equivalent to 10 megabytes (MB).
+            max_message_size: config.max_message_size.unwrap_or(10 * 1024 * 1024),
+            mail_tx: config.mail_tx.clone(),
+            pending: Vec::new(),
+            consumed: 0,
+            replies: Vec::new(),
+            discard: 0,
+            authenticator: config.authenticator.clone(),
+            credential_store: config.credential_store.clone(),
+            token_verifier: config.token_verifier.clone(),
+            require_auth: config.require_auth,
+            require_tls: config.require_tls,
+            implicit_tls: config.implicit_tls,
+            authenticated: None,
+            sasl_response: None,
+            tls: None,
+            client_certificate_auth: config.client_certificate_auth,
+            session,
+            trace_headers: config.trace_headers,
+            message_handler: config.message_handler.clone(),
+            // Clients wait 10 minutes for the reply to the mail data (RFC 5321, section 4.5.3.2.6)
+            message_handler_timeout: config
+                .message_handler_timeout
+                .unwrap_or(Duration::from_secs(5 * 60)),
+            recipient_policy: config.recipient_policy.clone(),
+            pending_recipient: None,
+            sender_policy: config.sender_policy.clone(),
+            pending_sender: None,
+            connection_policy: config.connection_policy.clone(),
+            allowed_networks: config.allowed_networks.clone(),
+            denied_networks: config.denied_networks.clone(),
+            dnsbl: config.dnsbl.clone(),
         }
     }
 }
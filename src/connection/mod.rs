@@ -1,10 +1,29 @@
+mod auth;
 mod create;
+mod policy;
 mod process;
 mod rw;
-use std::{path::PathBuf, time::Duration};
+mod session;
+mod tls;
+mod trace;
+pub(crate) use session::unique_id;
+use ipnetwork::IpNetwork;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
+use async_std::channel::Sender;
 use thiserror::Error;
 use tokio::net::TcpStream;
+
+use crate::{
+    auth::{Authenticator, CredentialStore, Sasl, TokenVerifier},
+    dnsbl::{Dnsbl, DnsblListing},
+    hooks::{ConnectionPolicy, MessageHandler, RecipientPolicy, SenderPolicy},
+    server::SharedTlsConfig,
+};
 use tokio_rustls::server::TlsStream;
 
 #[derive(Error, Debug)]
@@ -23,6 +42,16 @@ pub enum ProcessingError {
     AlreadyEncrypted,
     #[error("Socket read")]
     SocketRead,
+    #[error("Could not forward mail")]
+    ForwardMail,
+    #[error("Line too long")]
+    LineTooLong,
+    #[error("Message too large")]
+    MessageTooLarge,
+    #[error("TLS handshake timed out")]
+    HandshakeTimeout,
+    #[error("Connection timed out")]
+    Timeout,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -33,18 +62,26 @@ pub enum ProcessingError {
    - `Initial`: The initial state of the connection.
    - `Ehlo`: The state after the EHLO command has been received.
    - `StartTls`: The state after the STARTTLS command has been received.
+   - `Auth`: The state during a SASL exchange started by the AUTH command.
    - `MailFrom`: The state after the MAIL FROM command has been received.
    - `Data`: The state after the DATA command has been received.
-   - `Invalid`: An invalid state.
+   - `Chunk`: The state after a BDAT command has been accepted, holding the size of the chunk and whether it is the last one.
+   - `Chunking`: The state after a BDAT chunk has been received, awaiting the next chunk.
+   - `Oversized`: The state after the mail data exceeded the maximum message size, the remaining data is discarded.
+   - `Received`: The state after the end of the mail data has been received, before the mail is forwarded.
 
 */
 pub enum State {
     Initial,
     Ehlo(String),
     StartTls,
+    Auth(String, Sasl),
     MailFrom(Mail),
     Data(Mail),
-    Invalid,
+    Chunk(Mail, usize, bool),
+    Chunking(Mail),
+    Oversized(String),
+    Received(Mail),
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -52,22 +89,109 @@ pub enum State {
 ## Mail struct
    The `Mail` struct represents an email message.
    It includes the following fields:
-   - `domain`: The domain of the email.
+   - `domain`: The domain the client sent with the EHLO or HELO command.
    - `from`: The sender of the email.
    - `to`: The recipients of the email.
    - `data`: The raw content of the email, including headers and body.
+   - `body`: The body type declared with the `BODY` parameter of MAIL FROM.
+   - `smtputf8`: Whether the client requested SMTPUTF8, allowing UTF-8 in addresses and headers.
+   - `authenticated`: The identity the client authenticated as with the AUTH command, if any.
+   - `tls`: The details of the TLS session the mail was received over, `None` if unencrypted.
+   - `session`: The session the mail was received in, shared by every mail of a connection.
+   - `queue_id`: The unique id of the mail, assigned when MAIL FROM is accepted.
+   - `received_at`: When the end of the mail data was received, `None` until then.
 */
 pub struct Mail {
     pub domain: String,
     pub from: String,
     pub to: Vec<String>,
     pub data: Vec<u8>,
+    pub body: BodyType,
+    pub smtputf8: bool,
+    pub authenticated: Option<String>,
+    pub tls: Option<TlsSession>,
+    pub session: Session,
+    pub queue_id: String,
+    pub received_at: Option<SystemTime>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/**
+## Session
+   The `Session` struct describes the connection a client opened, for logging and abuse handling.
+   It includes the following fields:
+   - `id`: The unique id of the session.
+   - `remote_addr`: The address and port of the client.
+   - `local_addr`: The address and port of the server the client connected to.
+   - `helo`: The name the client sent with its last EHLO or HELO command, if any.
+   - `esmtp`: Whether the client greeted with EHLO rather than HELO.
+   - `trusted`: Whether the client connected from a trusted relay network, which bypasses the
+     authentication requirement and the sender and recipient policies.
+   - `dnsbl`: The DNS blocklists the client is listed on, among those that only tag clients.
+   - `connected_at`: When the connection was accepted.
+*/
+pub struct Session {
+    pub id: String,
+    pub remote_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub helo: Option<String>,
+    pub esmtp: bool,
+    pub trusted: bool,
+    pub dnsbl: Vec<DnsblListing>,
+    pub connected_at: SystemTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/**
+## TLS session
+   The `TlsSession` struct describes the TLS session negotiated with a client.
+   It includes the following fields:
+   - `version`: The protocol version, e.g. `TLSv1.3`.
+   - `cipher_suite`: The cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`.
+   - `sni`: The server name the client sent during the handshake (SNI), if any.
+   - `client_certificate`: The verified certificate the client presented (mutual TLS), if any.
+*/
+pub struct TlsSession {
+    pub version: String,
+    pub cipher_suite: String,
+    pub sni: Option<String>,
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/**
+## Client certificate
+   The `ClientCertificate` struct describes the certificate a client presented during the
+   TLS handshake, which was verified with the CA bundle of the server.
+   It includes the following fields:
+   - `subject`: The subject of the certificate, e.g. `CN=relay.example.com,O=Example`.
+   - `fingerprint`: The SHA-256 fingerprint of the certificate, as colon separated hex.
+*/
+pub struct ClientCertificate {
+    pub subject: String,
+    pub fingerprint: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/**
+## Body type
+   The `BodyType` enum represents the content of a message as declared by the client.
+   It includes the following variants:
+   - `SevenBit`: The message only contains 7-bit ASCII, the default.
+   - `EightBitMime`: The message may contain 8-bit data (RFC 6152).
+   - `BinaryMime`: The message may contain binary data and is sent with BDAT (RFC 3030).
+*/
+pub enum BodyType {
+    #[default]
+    SevenBit,
+    EightBitMime,
+    BinaryMime,
 }
 
 #[derive(Debug)]
 pub enum Stream {
     Plain(TcpStream),
-    Encrypted(TlsStream<TcpStream>),
+    Encrypted(Box<TlsStream<TcpStream>>),
 }
 
 #[derive(Debug)]
@@ -76,16 +200,14 @@ pub enum Stream {
    The `TlsConfig` enum represents the configuration for TLS encryption.
    It includes the following variants:
    - `Plain`: Represents a plain connection without encryption.
-   - `Encrypted`: Represents an encrypted connection with the provided certificate and key paths.
+   - `Encrypted`: Represents an encrypted connection with the TLS configuration shared by all
+     connections, which is read when the handshake begins so that reloaded certificates are used.
 
    This enum is used to configure the connection to use TLS encryption.
 */
 pub enum TlsConfig {
     Plain,
-    Encrypted {
-        cert_path: PathBuf,
-        key_path: PathBuf,
-    },
+    Encrypted(SharedTlsConfig),
 }
 
 #[derive(Debug)]
@@ -99,6 +221,36 @@ pub enum TlsConfig {
    - `tls_config`: The TLS configuration for the connection.
    - `domain`: The domain of the connection.
    - `timeout`: The duration after which the connection will timeout.
+   - `max_message_size`: The maximum size of a message (bytes).
+   - `mail_tx`: The sender used to forward every received mail.
+   - `pending`: Data received from the stream, kept until the next read once processed.
+   - `consumed`: How many bytes at the start of `pending` have already been processed.
+   - `replies`: Replies that have not been sent to the client yet.
+   - `discard`: The size of a rejected BDAT chunk that still has to be read and discarded.
+   - `authenticator`: The authenticator used to validate PLAIN and LOGIN credentials.
+   - `credential_store`: The credential store used to validate SCRAM-SHA-256 and CRAM-MD5 exchanges.
+   - `token_verifier`: The token verifier used to validate OAUTHBEARER and XOAUTH2 tokens.
+   - `require_auth`: Whether clients must authenticate before sending mail.
+   - `require_tls`: Whether MAIL, RCPT and AUTH are refused until the connection is encrypted.
+   - `implicit_tls`: Whether the TLS handshake is performed before the greeting, instead of with STARTTLS.
+   - `authenticated`: The identity the client authenticated as, if any.
+   - `sasl_response`: An initial SASL response sent with the AUTH command that has not been processed yet.
+   - `tls`: The details of the TLS session once the connection is encrypted.
+   - `client_certificate_auth`: Whether a verified client certificate counts as authentication.
+   - `session`: The session of the connection, recorded on every mail.
+   - `trace_headers`: Whether the `Return-Path:` and `Received:` headers are prepended to every mail.
+   - `message_handler`: The handler deciding whether a received mail is accepted.
+   - `message_handler_timeout`: The duration after which a mail the handler has not decided on is
+     answered with `451`.
+   - `recipient_policy`: The policy deciding whether each recipient is accepted.
+   - `pending_recipient`: A recipient sent with RCPT TO that the recipient policy has not checked yet.
+   - `sender_policy`: The policy deciding whether the sender of MAIL FROM is accepted.
+   - `pending_sender`: A transaction started with MAIL FROM and its ESMTP parameters, that the
+     sender policy has not checked yet.
+   - `connection_policy`: The policy deciding whether the client is served, before it is greeted.
+   - `allowed_networks`: The networks clients must connect from, any network if empty.
+   - `denied_networks`: The networks from which clients are refused.
+   - `dnsbl`: The DNS blocklists the address of the client is looked up on.
 */
 pub struct Connection {
     pub buffer_size: Option<usize>,
@@ -107,4 +259,32 @@ pub struct Connection {
     pub tls_config: TlsConfig,
     pub domain: String,
     pub timeout: Duration,
+    pub max_message_size: usize,
+    pub mail_tx: Sender<Mail>,
+    pub pending: Vec<u8>,
+    pub consumed: usize,
+    pub replies: Vec<u8>,
+    pub discard: usize,
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub credential_store: Option<Arc<dyn CredentialStore>>,
+    pub token_verifier: Option<Arc<dyn TokenVerifier>>,
+    pub require_auth: bool,
+    pub require_tls: bool,
+    pub implicit_tls: bool,
+    pub authenticated: Option<String>,
+    pub sasl_response: Option<Vec<u8>>,
+    pub tls: Option<TlsSession>,
+    pub client_certificate_auth: bool,
+    pub session: Session,
+    pub trace_headers: bool,
+    pub message_handler: Option<Arc<dyn MessageHandler>>,
+    pub message_handler_timeout: Duration,
+    pub recipient_policy: Option<Arc<dyn RecipientPolicy>>,
+    pub pending_recipient: Option<String>,
+    pub sender_policy: Option<Arc<dyn SenderPolicy>>,
+    pub pending_sender: Option<(Mail, Vec<String>)>,
+    pub connection_policy: Option<Arc<dyn ConnectionPolicy>>,
+    pub allowed_networks: Vec<IpNetwork>,
+    pub denied_networks: Vec<IpNetwork>,
+    pub dnsbl: Option<Arc<Dnsbl>>,
 }
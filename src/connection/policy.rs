@@ -0,0 +1,143 @@
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+use tokio::time::sleep;
+
+use super::{Connection, Mail, State};
+use crate::{
+    dnsbl::DnsblAction,
+    hooks::Admit,
+    parser::{
+        responses::{multiline, BAD_SEQUENCE, NO_SERVICE},
+        Response,
+    },
+};
+
+/**
+   Checks whether an address belongs to any of the networks. IPv4 clients of a dual-stack
+   listener, which appear as IPv4-mapped IPv6 addresses, are matched against IPv4 networks.
+*/
+pub fn in_networks(networks: &[IpNetwork], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    networks.iter().any(|network| network.contains(ip))
+}
+
+impl Connection {
+    /**
+       Checks the client against the network lists, then asks the connection policy whether
+       to serve it, and waits before the greeting if the policy asks for a delay.
+       Returns the reply refusing the connection, if any. If any check is configured, a client
+       whose address cannot be determined is refused.
+    */
+    pub(super) async fn check_connection(&mut self) -> Option<Response> {
+        if self.allowed_networks.is_empty()
+            && self.denied_networks.is_empty()
+            && self.dnsbl.is_none()
+            && self.connection_policy.is_none()
+        {
+            return None;
+        }
+        // A client that cannot be checked is refused instead of slipping through
+        let Some(remote_addr) = self.session.remote_addr else {
+            log::error!("Connection refused: the address of the client is unknown");
+            return Some(NO_SERVICE.into());
+        };
+
+        // Trusted relays are always served, other clients must not be denied and, if there is
+        // an allow list, must be on it
+        let ip = remote_addr.ip();
+        let allowed = self.allowed_networks.is_empty() || in_networks(&self.allowed_networks, ip);
+        if !self.session.trusted && (in_networks(&self.denied_networks, ip) || !allowed) {
+            log::error!(
+                "Connection from {} refused by the network lists",
+                remote_addr
+            );
+            return Some(NO_SERVICE.into());
+        }
+
+        // Trusted relays are not looked up, other clients are refused if a blocklist says so
+        if let (Some(dnsbl), false) = (self.dnsbl.clone(), self.session.trusted) {
+            let listings = dnsbl.check(ip).await;
+            if let Some(listing) = listings.iter().find(|l| l.action == DnsblAction::Reject) {
+                log::error!("Connection from {} refused: {:?}", remote_addr, listing);
+                let mut text = format!(
+                    "5.7.1 Service unavailable; client [{}] blocked using {}",
+                    ip, listing.zone
+                );
+                if let Some(meaning) = &listing.meaning {
+                    text.push_str(&format!("; {}", meaning));
+                }
+                return Some(multiline(554, &[text]).into());
+            }
+            self.session.dnsbl = listings;
+        }
+
+        let connection_policy = self.connection_policy.clone()?;
+        let Some(local_addr) = self.session.local_addr else {
+            log::error!(
+                "Connection from {} refused: the local address is unknown",
+                remote_addr
+            );
+            return Some(NO_SERVICE.into());
+        };
+        match connection_policy
+            .check_connection(remote_addr, local_addr)
+            .await
+        {
+            Ok(Admit::Greet) => None,
+            Ok(Admit::Delay(delay)) => {
+                log::info!("Delaying the greeting of {} by {:?}", remote_addr, delay);
+                sleep(delay).await;
+                None
+            }
+            Err(reject) => {
+                log::error!("Connection from {} refused: {:?}", remote_addr, reject);
+                Some(reject.reply(421, 554))
+            }
+        }
+    }
+
+    /**
+       Asks the sender policy whether to accept the sender of a new transaction, and starts
+       the transaction if it is accepted.
+    */
+    pub(super) async fn check_sender(&mut self, mail: Mail, parameters: Vec<String>) -> Response {
+        let Some(sender_policy) = self.sender_policy.clone() else {
+            return BAD_SEQUENCE.into();
+        };
+        match sender_policy.check_sender(&mail, &parameters).await {
+            Ok(accept) => {
+                log::info!("Sender accepted: {:?}", mail.from);
+                self.state = State::MailFrom(mail);
+                accept.reply(250)
+            }
+            Err(reject) => {
+                log::error!("Sender rejected: {:?}: {:?}", mail.from, reject);
+                reject.reply(451, 550)
+            }
+        }
+    }
+
+    /**
+       Asks the recipient policy whether to accept a recipient of the current transaction,
+       and adds it to the mail if it is accepted.
+    */
+    pub(super) async fn check_recipient(&mut self, recipient: String) -> Response {
+        let (Some(recipient_policy), State::MailFrom(mail)) =
+            (self.recipient_policy.clone(), &mut self.state)
+        else {
+            return BAD_SEQUENCE.into();
+        };
+        match recipient_policy.check_recipient(&recipient, mail).await {
+            Ok(accept) => {
+                log::info!("Recipient accepted: {:?}", recipient);
+                mail.to.push(recipient);
+                accept.reply(251)
+            }
+            Err(reject) => {
+                log::error!("Recipient rejected: {:?}: {:?}", recipient, reject);
+                reject.reply(450, 550)
+            }
+        }
+    }
+}
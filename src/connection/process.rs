@@ -1,48 +1,151 @@
-use super::{Connection, Mail, ProcessingError};
+use super::{Connection, Mail, ProcessingError, TlsSession};
 use crate::{
     connection::{State, Stream, TlsConfig},
-    parser::{parse_and_execute, responses::QUIT},
+    parser::{
+        parse_and_execute,
+        responses::{BAD_SEQUENCE, LINE_TOO_LONG, LOCAL_ERROR, MESSAGE_TOO_LARGE, OK, QUIT},
+        Response,
+    },
 };
+use std::time::SystemTime;
 use tokio::time::timeout;
-use tokio_tls_upgrade::upgrade_tcp_stream;
+use tokio_rustls::TlsAcceptor;
 
 impl Connection {
     pub async fn process_buffer(&mut self, buf: &mut [u8]) -> Result<bool, ProcessingError> {
-        match self.read(buf).await {
-            Ok(n) => {
-                if n == 0 {
-                    log::info!("Connection closed by client");
-                    return Err(ProcessingError::ConnectionClosed);
-                }
-                // Read the command as a string and convert it to lowercase.
-                let command = &buf[..n];
-                log::info!("Received command: {:?}", command);
-                let result = parse_and_execute(self, command)?;
-
-                // If the result is not empty, we write it to the socket.
-                if !result.is_empty() {
-                    if let Ok(()) = self.write(result).await {
-                        // If the result is QUIT, we close the connection.
-                        if result == QUIT {
-                            log::info!("Closing connection");
-                            return Ok(false);
-                        }
-                        return Ok(true);
-                    } else {
-                        return Err(ProcessingError::SendResponse);
-                    }
+        // Commands have to be received and executed within the timeout, while the data of
+        // BDAT chunks only has to keep arriving, since a chunk may take longer than a command.
+        // Delivering a mail has its own deadline since the message handler may take longer.
+        let mut result = if let State::Chunk(..) = self.state {
+            // The data of a BDAT chunk is read as is, without line framing or dot-stuffing
+            self.receive_chunk(buf).await?
+        } else {
+            match timeout(self.timeout, self.execute(buf)).await {
+                // An over-long line is answered and skipped, instead of closing the connection
+                Ok(Err(ProcessingError::LineTooLong)) => self.line_too_long(),
+                Ok(result) => result?,
+                Err(_) => return Err(ProcessingError::Timeout),
+            }
+        };
+
+        // The data of a rejected BDAT chunk follows the command and is skipped
+        if self.discard > 0 {
+            let size = std::mem::take(&mut self.discard);
+            self.skip_chunk(buf, size, &result).await?;
+        }
+
+        // Once the end of the mail data has been received, we deliver the mail and
+        // return to the state after EHLO so that another transaction can begin.
+        if let State::Received(mail) = &mut self.state {
+            // The mail is moved out of the state, since it can be large
+            let mut mail = std::mem::take(mail);
+            self.state = State::Ehlo(mail.domain.clone());
+            mail.received_at = Some(SystemTime::now());
+            // The trace headers record where the mail came from for the mailbox store
+            if self.trace_headers {
+                let headers = mail.trace_headers(&self.domain);
+                mail.data.splice(0..0, headers.into_bytes());
+            }
+            result = self.deliver(mail).await;
+        }
+
+        // If the result is not empty, we queue it to be sent.
+        if !result.is_empty() {
+            log::info!("Queueing response: {:?}", &String::from_utf8_lossy(&result));
+            self.replies.extend_from_slice(&result);
+        }
+
+        // Replies to pipelined commands are sent together once the whole batch has been
+        // processed (RFC 2920). They are also sent before closing or upgrading the connection.
+        let batch_processed = match self.state {
+            State::Chunk(_, size, _) => self.unprocessed().len() < size,
+            _ => !self.has_pending_line(),
+        };
+        if *result == *QUIT || self.state == State::StartTls || batch_processed {
+            match timeout(self.timeout, self.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    log::error!("Error sending response: {}", e);
+                    return Err(ProcessingError::SendResponse);
                 }
-                log::info!("Sending response: {:?}", &String::from_utf8_lossy(result));
-                Ok(true)
+                Err(_) => return Err(ProcessingError::Timeout),
             }
-            Err(e) => {
-                log::error!("Error reading from socket: {}", e);
-                Err(ProcessingError::SocketRead)
+        }
+
+        // If the result is QUIT, we close the connection.
+        if *result == *QUIT {
+            log::info!("Closing connection");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Reads and executes the next command, SASL response or line of mail data
+    async fn execute(&mut self, buf: &mut [u8]) -> Result<Response, ProcessingError> {
+        let result = if let State::Auth(..) = self.state {
+            // During a SASL exchange every line is a response to the last challenge
+            let response = match self.sasl_response.take() {
+                Some(response) => response,
+                None => self.read_line(buf).await?,
+            };
+            self.authenticate(&response).await
+        } else {
+            // Commands and mail data are processed line by line
+            let line = self.read_line(buf).await?;
+            let result = parse_and_execute(self, &line)?;
+            // A transaction only starts, and a recipient is only added, once the policies accept them
+            if let Some((mail, parameters)) = self.pending_sender.take() {
+                self.check_sender(mail, parameters).await
+            } else if let Some(recipient) = self.pending_recipient.take() {
+                self.check_recipient(recipient).await
+            } else {
+                result
+            }
+        };
+        Ok(result)
+    }
+
+    /// Answers a line that was too long to be read (RFC 5321, section 4.5.3.1.4).
+    /// Within the mail data, the mail is discarded and rejected once its end is received.
+    fn line_too_long(&mut self) -> Response {
+        match std::mem::replace(&mut self.state, State::Initial) {
+            State::Data(mail) => {
+                self.state = State::Oversized(mail.domain);
+                Response::default()
+            }
+            // The mail is already being discarded, it is rejected once
+            State::Oversized(domain) => {
+                self.state = State::Oversized(domain);
+                Response::default()
+            }
+            // A SASL exchange cannot go on without the response
+            State::Auth(domain, _) => {
+                self.state = State::Ehlo(domain);
+                LINE_TOO_LONG.into()
+            }
+            state => {
+                self.state = state;
+                LINE_TOO_LONG.into()
             }
         }
     }
 
-    pub async fn process(mut self) -> Result<Mail, ProcessingError> {
+    pub async fn process(mut self) -> Result<(), ProcessingError> {
+        // The connection policy decides before any effort is spent on the client
+        let refusal = self.check_connection().await;
+
+        // With implicit TLS, the handshake happens before anything is sent (RFC 8314)
+        if self.implicit_tls {
+            self = self.upgrade().await?;
+        }
+
+        // A refused client is told why instead of being greeted, and the connection is closed
+        if let Some(reply) = refusal {
+            self.replies.extend_from_slice(&reply);
+            self.flush().await?;
+            return Ok(());
+        }
+
         // As per RFC, the server should send a 220 greeting message when a connection is established.
         self.greet().await?;
 
@@ -54,64 +157,171 @@ impl Connection {
 
         loop {
             log::info!("Waiting for data...");
-            // We use the timeout function from tokio to set a timeout for the connection.
-            // If the timeout is reached, the connection is closed.
-            match timeout(self.timeout, self.process_buffer(&mut buf)).await {
-                Ok(Ok(keep_open)) => {
+            // If the client does not send a command or read the replies within the timeout,
+            // the connection is closed.
+            match self.process_buffer(&mut buf).await {
+                Ok(keep_open) => {
                     // If the buffer is processed successfully and the connection is not closed, we continue.
                     if !keep_open {
                         break;
                     }
                 }
-                Ok(Err(e)) => {
-                    log::error!("Error processing buffer: {}", e);
-                    // Even if there might be an error, we still want to return the email if it was received.
-                    match self.state {
-                        State::Data(mail) => return Ok(mail),
-                        _ => return Err(e),
-                    };
-                }
-                Err(_) => {
+                Err(ProcessingError::Timeout) => {
                     log::error!("Connection timed out. Closing connection...");
                     break;
                 }
+                Err(e) => {
+                    log::error!("Error processing buffer: {}", e);
+                    return Err(e);
+                }
             }
 
             // If the state is that we should start TLS, we upgrade the connection to use TLS.
             if self.state == State::StartTls {
-                log::info!("Upgrading connection to use TLS");
-                match self.stream {
-                    Stream::Plain(stream) => match self.tls_config {
-                        // We cannot upgrade to TLS if no certificate is provided.
-                        TlsConfig::Plain => {
-                            log::error!("TLS upgrade requested but no certificate provided");
-                            return Err(ProcessingError::NoCertificate);
+                self = self.upgrade().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Upgrades the connection to use TLS
+    async fn upgrade(mut self) -> Result<Self, ProcessingError> {
+        log::info!("Upgrading connection to use TLS");
+        match self.stream {
+            Stream::Plain(stream) => match self.tls_config {
+                // We cannot upgrade to TLS if no certificate is provided.
+                TlsConfig::Plain => {
+                    log::error!("TLS upgrade requested but no certificate provided");
+                    Err(ProcessingError::NoCertificate)
+                }
+                // If the certificate is provided, we upgrade the connection to use TLS.
+                TlsConfig::Encrypted(ref tls_config) => {
+                    // We upgrade the connection to use TLS.
+                    let acceptor = TlsAcceptor::from(tls_config.current());
+                    // A client that never completes the handshake must not hold the connection
+                    let tls_stream = match timeout(self.timeout, acceptor.accept(stream)).await {
+                        Ok(tls_stream) => tls_stream?,
+                        Err(_) => {
+                            log::error!("TLS handshake timed out");
+                            return Err(ProcessingError::HandshakeTimeout);
                         }
-                        // If the certificate is provided, we upgrade the connection to use TLS.
-                        TlsConfig::Encrypted {
-                            ref cert_path,
-                            ref key_path,
-                        } => {
-                            // We upgrade the connection to use TLS.
-                            self.stream = Stream::Encrypted(
-                                upgrade_tcp_stream(stream, cert_path.clone(), key_path.clone())
-                                    .await?,
-                            );
-                            self.state = State::Initial;
-                            log::info!("Connection upgraded to TLS");
+                    };
+                    let tls = TlsSession::new(tls_stream.get_ref().1);
+                    log::info!("TLS session: {:?}", tls);
+                    self.stream = Stream::Encrypted(Box::new(tls_stream));
+                    self.state = State::Initial;
+                    // The client has to authenticate again over the encrypted connection,
+                    // unless its certificate counts as authentication
+                    self.authenticated = match &tls.client_certificate {
+                        Some(certificate) if self.client_certificate_auth => {
+                            log::info!("Authenticated by certificate as {:?}", certificate.subject);
+                            Some(certificate.subject.clone())
                         }
-                    },
-                    _ => {
-                        log::error!("Cannot upgrade an already encrypted connection to TLS");
-                        return Err(ProcessingError::AlreadyEncrypted);
-                    }
+                        _ => None,
+                    };
+                    self.tls = Some(tls);
+                    // Anything sent before the handshake must not be processed as encrypted input
+                    self.pending.clear();
+                    self.consumed = 0;
+                    log::info!("Connection upgraded to TLS");
+                    Ok(self)
                 }
+            },
+            _ => {
+                log::error!("Cannot upgrade an already encrypted connection to TLS");
+                Err(ProcessingError::AlreadyEncrypted)
+            }
+        }
+    }
+
+    /// Receives the data of a BDAT chunk (RFC 3030) and appends it to the mail
+    async fn receive_chunk(&mut self, buf: &mut [u8]) -> Result<Response, ProcessingError> {
+        let State::Chunk(mut mail, size, last) = std::mem::replace(&mut self.state, State::Initial)
+        else {
+            return Ok(BAD_SEQUENCE.into());
+        };
+
+        // If the message becomes too large, the chunk is read but not stored
+        if size > self.max_message_size.saturating_sub(mail.data.len()) {
+            log::error!("Message exceeds {} bytes", self.max_message_size);
+            self.skip_chunk(buf, size, MESSAGE_TOO_LARGE).await?;
+            self.state = State::Ehlo(mail.domain);
+            return Ok(MESSAGE_TOO_LARGE.into());
+        }
+
+        self.read_chunk(buf, size, Some(&mut mail.data)).await?;
+        if last {
+            log::info!("Data received successfully");
+            // The mail is complete, the reply is sent once it has been delivered
+            self.state = State::Received(mail);
+            Ok(Response::default())
+        } else {
+            self.state = State::Chunking(mail);
+            Ok(format!("250 {} octets received\r\n", size)
+                .into_bytes()
+                .into())
+        }
+    }
+
+    /// Skips the data of a BDAT chunk rejected with the given reply. A chunk larger than any
+    /// message is not read, since the client could keep the connection busy forever, so the
+    /// reply is sent right away and the connection is closed.
+    async fn skip_chunk(
+        &mut self,
+        buf: &mut [u8],
+        size: usize,
+        reply: &[u8],
+    ) -> Result<(), ProcessingError> {
+        if size > self.max_message_size {
+            log::error!("Chunk of {} bytes exceeds the maximum message size", size);
+            self.replies.extend_from_slice(reply);
+            self.flush().await?;
+            return Err(ProcessingError::MessageTooLarge);
+        }
+        self.read_chunk(buf, size, None).await
+    }
+
+    /// Delivers a received mail to the message handler, if any, and answers the client with
+    /// its decision. Accepted mail is forwarded to the mail channel.
+    async fn deliver(&mut self, mail: Mail) -> Response {
+        let Some(message_handler) = self.message_handler.clone() else {
+            return match self.forward(mail).await {
+                Ok(()) => OK.into(),
+                Err(_) => LOCAL_ERROR.into(),
+            };
+        };
+        let decision = timeout(
+            self.message_handler_timeout,
+            message_handler.on_message(&mail),
+        );
+        let Ok(decision) = decision.await else {
+            log::error!("Mail {} not handled in time", mail.queue_id);
+            return LOCAL_ERROR.into();
+        };
+        match decision {
+            Ok(accept) => {
+                log::info!("Mail {} accepted", mail.queue_id);
+                // The handler has taken responsibility for the mail, so it is accepted
+                // even if nobody listens on the mail channel anymore
+                let reply = accept.reply(250);
+                let _ = self.forward(mail).await;
+                reply
+            }
+            Err(reject) => {
+                log::error!("Mail {} rejected: {:?}", mail.queue_id, reject);
+                reject.reply(451, 554)
             }
         }
-        match self.state {
-            State::Data(mail) => Ok(mail),
-            _ => Err(ProcessingError::NoMail),
+    }
+
+    /// Forwards a received mail to the mail channel
+    async fn forward(&mut self, mail: Mail) -> Result<(), ProcessingError> {
+        if let Err(e) = self.mail_tx.send(mail).await {
+            log::error!("Error sending mail to channel: {}", e);
+            return Err(ProcessingError::ForwardMail);
         }
+        log::info!("Mail forwarded to channel");
+        Ok(())
     }
 
     /// Sends the initial SMTP greeting
@@ -1,6 +1,9 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
 
-use super::{Connection, Stream};
+use super::{Connection, ProcessingError, Stream};
 
 impl Connection {
     // Method to read from the stream
@@ -11,6 +14,124 @@ impl Connection {
         }
     }
 
+    /**
+       Reads the next line from the stream, including its terminating CRLF.
+       Whatever is received after the line is kept for the following calls, so lines
+       that are split across reads or merged within a single read are framed correctly.
+       A line may not be longer than the buffer used for reading, the rest of a longer
+       line is discarded and `LineTooLong` is returned.
+    */
+    pub async fn read_line(&mut self, buf: &mut [u8]) -> Result<Vec<u8>, ProcessingError> {
+        // Bytes that were already searched for a CRLF, minus one in case it was split
+        let mut searched = 0;
+        loop {
+            if let Some(position) = self.unprocessed()[searched..]
+                .windows(2)
+                .position(|window| window == b"\r\n")
+            {
+                let line = self.unprocessed()[..searched + position + 2].to_vec();
+                self.consumed += line.len();
+                return Ok(line);
+            }
+            if self.unprocessed().len() >= buf.len() {
+                log::error!("Line exceeds {} bytes", buf.len());
+                self.skip_line(buf).await?;
+                return Err(ProcessingError::LineTooLong);
+            }
+            searched = self.unprocessed().len().saturating_sub(1);
+            self.receive(buf).await?;
+        }
+    }
+
+    /**
+       Discards the data received up to the end of the current line, including its CRLF.
+    */
+    async fn skip_line(&mut self, buf: &mut [u8]) -> Result<(), ProcessingError> {
+        loop {
+            if let Some(position) = self
+                .unprocessed()
+                .windows(2)
+                .position(|window| window == b"\r\n")
+            {
+                self.consumed += position + 2;
+                return Ok(());
+            }
+            // A trailing CR is kept in case the LF arrives with the next read
+            self.consumed = self.pending.len() - usize::from(self.unprocessed().ends_with(b"\r"));
+            self.receive(buf).await?;
+        }
+    }
+
+    /**
+       Reads exactly `size` bytes, starting with the data that was already received.
+       The bytes are appended to `data`, or discarded if it is `None`. Only each read is bound
+       by the timeout, so that large chunks can be received over slow links.
+    */
+    pub async fn read_chunk(
+        &mut self,
+        buf: &mut [u8],
+        size: usize,
+        mut data: Option<&mut Vec<u8>>,
+    ) -> Result<(), ProcessingError> {
+        let mut remaining = size;
+        loop {
+            let available = remaining.min(self.unprocessed().len());
+            if let Some(ref mut data) = data {
+                data.extend_from_slice(&self.unprocessed()[..available]);
+            }
+            self.consumed += available;
+            remaining -= available;
+            if remaining == 0 {
+                return Ok(());
+            }
+            self.receive(buf).await?;
+        }
+    }
+
+    /**
+       Reads from the stream and appends whatever was received to the pending data.
+       The data that was already processed is dropped first, so that the pending data
+       is only moved once per read rather than once per line.
+    */
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<(), ProcessingError> {
+        self.pending.drain(..self.consumed);
+        self.consumed = 0;
+        // Every read has to complete within the timeout, however long the data it is part of
+        let Ok(read) = timeout(self.timeout, self.read(buf)).await else {
+            return Err(ProcessingError::Timeout);
+        };
+        match read {
+            Ok(0) => {
+                log::info!("Connection closed by client");
+                Err(ProcessingError::ConnectionClosed)
+            }
+            Ok(n) => {
+                self.pending.extend_from_slice(&buf[..n]);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Error reading from socket: {}", e);
+                Err(ProcessingError::SocketRead)
+            }
+        }
+    }
+
+    /**
+       The data received from the stream that has not been processed yet.
+    */
+    pub fn unprocessed(&self) -> &[u8] {
+        &self.pending[self.consumed..]
+    }
+
+    /**
+       Checks whether a complete line has already been received and is waiting to be processed.
+    */
+    pub fn has_pending_line(&self) -> bool {
+        self.unprocessed()
+            .windows(2)
+            .any(|window| window == b"\r\n")
+    }
+
     // Method to write to the stream
     pub async fn write(&mut self, buf: &[u8]) -> tokio::io::Result<()> {
         match &mut self.stream {
@@ -18,4 +139,16 @@ impl Connection {
             Stream::Encrypted(stream) => stream.write_all(buf).await,
         }
     }
+
+    /**
+       Sends all queued replies to the client.
+    */
+    pub async fn flush(&mut self) -> tokio::io::Result<()> {
+        let replies = std::mem::take(&mut self.replies);
+        self.write(&replies).await?;
+        match &mut self.stream {
+            Stream::Plain(stream) => stream.flush().await,
+            Stream::Encrypted(stream) => stream.flush().await,
+        }
+    }
 }
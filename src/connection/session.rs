@@ -0,0 +1,58 @@
+use std::{
+    sync::atomic::{AtomicU16, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{Session, Stream};
+
+static COUNTER: AtomicU16 = AtomicU16::new(0);
+
+/**
+   Generates an id that is unique within the process and unlikely to repeat across restarts,
+   made of the current time in microseconds and a counter, e.g. `5F2A3C41B7D20003`.
+*/
+pub fn unique_id() -> String {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros())
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:012X}{:04X}", micros & 0xFFFF_FFFF_FFFF, count)
+}
+
+impl Session {
+    /**
+       Starts the session of a connection that was just accepted.
+    */
+    pub fn new(stream: &Stream) -> Self {
+        let socket = match stream {
+            Stream::Plain(socket) => socket,
+            Stream::Encrypted(tls_stream) => tls_stream.get_ref().0,
+        };
+        Session {
+            id: unique_id(),
+            remote_addr: socket.peer_addr().ok(),
+            local_addr: socket.local_addr().ok(),
+            helo: None,
+            esmtp: false,
+            trusted: false,
+            dnsbl: Vec::new(),
+            connected_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            id: String::new(),
+            remote_addr: None,
+            local_addr: None,
+            helo: None,
+            esmtp: false,
+            trusted: false,
+            dnsbl: Vec::new(),
+            connected_at: UNIX_EPOCH,
+        }
+    }
+}
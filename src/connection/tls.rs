@@ -0,0 +1,66 @@
+use openssl::{sha::sha256, x509::X509};
+use tokio_rustls::rustls::ServerConnection;
+
+use super::{ClientCertificate, TlsSession};
+
+impl TlsSession {
+    /**
+       Describes the session negotiated on a connection once the handshake is complete.
+    */
+    pub fn new(connection: &ServerConnection) -> Self {
+        let version = connection
+            .protocol_version()
+            .and_then(|version| version.as_str())
+            .map(|version| version.replace('_', "."))
+            .unwrap_or_default();
+        let cipher_suite = connection
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+            .unwrap_or_default()
+            .to_string();
+        TlsSession {
+            version,
+            cipher_suite,
+            // The server name the client asked for, used to select the certificate
+            sni: connection.server_name().map(str::to_string),
+            // A certificate is only presented if the verifier of the server accepted it
+            client_certificate: connection
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .and_then(|certificate| ClientCertificate::from_der(certificate)),
+        }
+    }
+}
+
+impl ClientCertificate {
+    /**
+       Describes a DER encoded certificate presented by the client.
+       Returns `None` if the certificate cannot be parsed, or if an entry of its subject
+       contains a NUL character, which could hide the rest of the entry from other programs.
+    */
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let certificate = X509::from_der(der).ok()?;
+        let subject = certificate
+            .subject_name()
+            .entries()
+            .map(|entry| {
+                let key = entry.object().nid().short_name().unwrap_or("?");
+                let value = entry.data().to_string().ok()?;
+                if value.contains('\0') {
+                    return None;
+                }
+                Some(format!("{}={}", key, value))
+            })
+            .collect::<Option<Vec<_>>>()?
+            .join(",");
+        let fingerprint = sha256(der)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        Some(ClientCertificate {
+            subject,
+            fingerprint,
+        })
+    }
+}
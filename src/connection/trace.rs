@@ -0,0 +1,86 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Mail;
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/**
+   Formats a time as an RFC 5322 date in UTC, e.g. `Sun, 18 Oct 2026 09:30:00 +0000`.
+*/
+pub fn rfc5322_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // Converts the days since 1970-01-01 to a civil date, in eras of 400 years starting in March
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+impl Mail {
+    /**
+       Builds the `Return-Path:` and `Received:` trace headers (RFC 5321, section 4.4) that are
+       prepended to the data of the mail, as received by the server of the given domain.
+    */
+    pub fn trace_headers(&self, domain: &str) -> String {
+        let remote_ip = match self.session.remote_addr {
+            Some(addr) => format!("[{}]", addr.ip()),
+            None => "unknown".to_string(),
+        };
+        // The protocol names are registered by RFC 3848 and RFC 6531
+        let protocol = format!(
+            "{}{}{}",
+            match (self.smtputf8, self.session.esmtp) {
+                (true, _) => "UTF8SMTP",
+                (false, true) => "ESMTP",
+                (false, false) => "SMTP",
+            },
+            if self.tls.is_some() { "S" } else { "" },
+            if self.authenticated.is_some() {
+                "A"
+            } else {
+                ""
+            }
+        );
+        // The recipient is only disclosed when there is a single one
+        let recipient = match self.to.as_slice() {
+            [recipient] => format!("\r\n\tfor <{}>", recipient),
+            _ => String::new(),
+        };
+        format!(
+            "Return-Path: <{}>\r\nReceived: from {} ({})\r\n\tby {} with {} id {}{};\r\n\t{}\r\n",
+            self.from,
+            self.domain,
+            remote_ip,
+            domain,
+            protocol,
+            self.queue_id,
+            recipient,
+            rfc5322_date(self.received_at.unwrap_or_else(SystemTime::now))
+        )
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    proto::op::ResponseCode,
+    TokioAsyncResolver,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/**
+## DNSBL action
+   The `DnsblAction` enum represents what happens to a client listed in a DNS blocklist.
+   It includes the following variants:
+   - `Reject`: The connection is refused with `554` before the client is greeted.
+   - `Tag`: The client is served and the listing is recorded in `Session::dnsbl`.
+*/
+pub enum DnsblAction {
+    Reject,
+    Tag,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/**
+## DNSBL zone
+   The `DnsblZone` struct describes a DNS blocklist, e.g. `zen.spamhaus.org`.
+   It includes the following fields:
+   - `zone`: The domain of the blocklist.
+   - `action`: What happens to listed clients.
+   - `codes`: The return codes that count as a listing, with their meaning, e.g. `127.0.0.2`
+     for `Spam source`. If empty, any address in `127.0.0.0/8` counts as a listing.
+*/
+pub struct DnsblZone {
+    pub zone: String,
+    pub action: DnsblAction,
+    pub codes: HashMap<Ipv4Addr, String>,
+}
+
+impl DnsblZone {
+    /**
+       Describes a blocklist on which any return code counts as a listing.
+    */
+    pub fn new(zone: &str, action: DnsblAction) -> Self {
+        DnsblZone {
+            zone: zone.trim_end_matches('.').to_lowercase(),
+            action,
+            codes: HashMap::new(),
+        }
+    }
+
+    /**
+       Counts the given return code as a listing, with its meaning.
+    */
+    pub fn code(mut self, code: Ipv4Addr, meaning: &str) -> Self {
+        self.codes.insert(code, meaning.to_string());
+        self
+    }
+
+    /**
+       The name queried for an address, made of its octets (IPv4) or nibbles (IPv6) in
+       reverse order, e.g. `4.3.2.1.zen.spamhaus.org.` for `1.2.3.4`.
+    */
+    pub fn query_name(&self, ip: IpAddr) -> String {
+        let labels = match ip.to_canonical() {
+            IpAddr::V4(ip) => ip.octets().iter().rev().map(u8::to_string).collect(),
+            IpAddr::V6(ip) => ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0x0f, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect::<Vec<_>>(),
+        };
+        format!("{}.{}.", labels.join("."), self.zone)
+    }
+
+    /**
+       Interprets an address returned by the blocklist, returning the listing it stands for.
+    */
+    fn listing(&self, code: Ipv4Addr) -> Option<DnsblListing> {
+        let meaning = if self.codes.is_empty() {
+            // Addresses outside of 127.0.0.0/8 report an error of the blocklist, not a listing
+            if code.octets()[0] != 127 {
+                return None;
+            }
+            None
+        } else {
+            Some(self.codes.get(&code)?.clone())
+        };
+        Some(DnsblListing {
+            zone: self.zone.clone(),
+            action: self.action,
+            code,
+            meaning,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/**
+## DNSBL listing
+   The `DnsblListing` struct describes a blocklist on which a client is listed.
+   It includes the following fields:
+   - `zone`: The domain of the blocklist.
+   - `action`: What happens to listed clients.
+   - `code`: The return code of the blocklist, e.g. `127.0.0.2`.
+   - `meaning`: The meaning of the return code, if the zone describes it.
+*/
+pub struct DnsblListing {
+    pub zone: String,
+    pub action: DnsblAction,
+    pub code: Ipv4Addr,
+    pub meaning: Option<String>,
+}
+
+#[derive(Debug)]
+/**
+## DNS blocklists
+   The `Dnsbl` struct looks up the address of connecting clients on DNS blocklists (RFC 5782).
+   All zones are queried at once, and the listings of an address are cached for a while,
+   10 minutes by default, so that a client reconnecting is not looked up again.
+   Lookups that fail, e.g. because the blocklist does not answer, do not count as a listing,
+   and the address is looked up again on the next connection.
+
+   ```rust,no_run
+   use minismtp::dnsbl::{Dnsbl, DnsblAction, DnsblZone};
+
+   let dnsbl = Dnsbl::new(vec![
+       DnsblZone::new("zen.spamhaus.org", DnsblAction::Reject)
+           .code("127.0.0.2".parse().unwrap(), "Spam source")
+           .code("127.0.0.4".parse().unwrap(), "Exploited host"),
+       DnsblZone::new("bl.spamcop.net", DnsblAction::Tag),
+   ])
+   .unwrap();
+   ```
+*/
+pub struct Dnsbl {
+    resolver: TokioAsyncResolver,
+    zones: Vec<DnsblZone>,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<IpAddr, (Instant, Vec<DnsblListing>)>>,
+}
+
+impl Dnsbl {
+    /**
+       Queries the given zones with the resolvers configured on the system.
+       Returns an error if the system configuration cannot be read.
+    */
+    pub fn new(zones: Vec<DnsblZone>) -> Result<Self, ResolveError> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        Ok(Self::with_resolver_from(zones, resolver))
+    }
+
+    /**
+       Queries the given zones with the given resolver configuration, e.g. a local
+       caching resolver, since public resolvers are often refused by blocklists.
+    */
+    pub fn with_resolver(
+        zones: Vec<DnsblZone>,
+        config: ResolverConfig,
+        options: ResolverOpts,
+    ) -> Self {
+        Self::with_resolver_from(zones, TokioAsyncResolver::tokio(config, options))
+    }
+
+    fn with_resolver_from(zones: Vec<DnsblZone>, resolver: TokioAsyncResolver) -> Self {
+        Dnsbl {
+            resolver,
+            zones,
+            cache_ttl: Duration::from_secs(600),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+       Sets how long the listings of an address are cached.
+    */
+    pub fn cache_ttl(mut self, cache_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    /**
+       Looks up an address on every zone and returns the listings.
+    */
+    pub async fn check(&self, ip: IpAddr) -> Vec<DnsblListing> {
+        let ip = ip.to_canonical();
+        if let Some(listings) = self.cached(ip) {
+            return listings;
+        }
+
+        let lookups = self.zones.iter().map(|zone| self.lookup(zone, ip));
+        let results = join_all(lookups).await;
+        let failed = results.iter().any(Option::is_none);
+        let listings = results.into_iter().flatten().flatten().collect::<Vec<_>>();
+        // The listings are incomplete if a lookup failed, so they are not cached
+        if failed {
+            return listings;
+        }
+
+        let mut cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Expired entries are dropped so that the cache does not grow with every client
+        let now = Instant::now();
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.insert(ip, (now + self.cache_ttl, listings.clone()));
+        listings
+    }
+
+    /**
+       The listings of an address looked up recently, if any.
+    */
+    fn cached(&self, ip: IpAddr) -> Option<Vec<DnsblListing>> {
+        let cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match cache.get(&ip) {
+            Some((expires, listings)) if *expires > Instant::now() => Some(listings.clone()),
+            _ => None,
+        }
+    }
+
+    /**
+       Looks up an address on a zone and returns the listings for the returned codes,
+       or `None` if the lookup failed.
+    */
+    async fn lookup(&self, zone: &DnsblZone, ip: IpAddr) -> Option<Vec<DnsblListing>> {
+        let name = zone.query_name(ip);
+        match self.resolver.ipv4_lookup(name.as_str()).await {
+            Ok(lookup) => Some(
+                lookup
+                    .iter()
+                    .filter_map(|code| zone.listing(code.0))
+                    .collect(),
+            ),
+            // Unlisted addresses do not exist in the zone, other response codes are failures
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ResolveErrorKind::NoRecordsFound {
+                        response_code: ResponseCode::NXDomain | ResponseCode::NoError,
+                        ..
+                    }
+                ) =>
+            {
+                Some(Vec::new())
+            }
+            Err(e) => {
+                log::error!("Could not look up {}: {}", name, e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::{fmt::Debug, net::SocketAddr, time::Duration};
+
+use async_trait::async_trait;
+
+use crate::{
+    connection::Mail,
+    parser::{
+        responses::{multiline, OK},
+        Response,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/**
+## Accept enum
+   The `Accept` enum represents the reply sent to a client when a hook accepts its request.
+   It includes the following variants:
+   - `Ok`: The usual `250 OK` reply.
+   - `Reply`: A `250` reply with the given text, e.g. `Queued as 4B2D1F`.
+   - `NotLocal`: A `251` reply with the given text, for a recipient that is not local but
+     to which the mail will be forwarded, e.g. `User not local; will forward to <user@example.com>`.
+     Sender policies and message handlers answer it with `250` like `Reply`, as `251` is only
+     valid for recipients.
+*/
+pub enum Accept {
+    Ok,
+    Reply(String),
+    NotLocal(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/**
+## Reject enum
+   The `Reject` enum represents the reply sent to a client when a hook rejects its request.
+   It includes the following variants:
+   - `Temporary`: A transient failure with the given text, the client should try again later.
+   - `Permanent`: A permanent failure with the given text, the client should not try again.
+   - `Reply`: A failure with the given `4xx` or `5xx` code and text, e.g. `452 Too many recipients`.
+
+   The reply code of temporary and permanent failures depends on the command that is rejected,
+   e.g. `451` and `554` after the mail data. Every line of the text is sent as a line of the reply.
+*/
+pub enum Reject {
+    Temporary(String),
+    Permanent(String),
+    Reply(u16, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/**
+## Admit enum
+   The `Admit` enum represents how a connection the connection policy allowed is greeted.
+   It includes the following variants:
+   - `Greet`: The client is greeted right away.
+   - `Delay`: The client is greeted after the given delay, e.g. to slow down suspicious clients.
+*/
+pub enum Admit {
+    Greet,
+    Delay(Duration),
+}
+
+/**
+   Splits the text of a reply into lines, so that line breaks cannot end the reply early.
+*/
+fn lines(text: &str) -> Vec<String> {
+    match text.lines().map(str::to_string).collect::<Vec<_>>() {
+        lines if lines.is_empty() => vec![String::new()],
+        lines => lines,
+    }
+}
+
+impl Accept {
+    /**
+       Builds the `250` reply sent to the client, with the code of the command for `NotLocal`.
+    */
+    pub(crate) fn reply(&self, not_local: u16) -> Response {
+        match self {
+            Accept::Ok => OK.into(),
+            Accept::Reply(text) => multiline(250, &lines(text)).into(),
+            Accept::NotLocal(text) => multiline(not_local, &lines(text)).into(),
+        }
+    }
+}
+
+impl Reject {
+    /**
+       Builds the reply sent to the client, with the temporary or permanent code of the command.
+    */
+    pub(crate) fn reply(&self, temporary: u16, permanent: u16) -> Response {
+        match self {
+            Reject::Temporary(text) => multiline(temporary, &lines(text)).into(),
+            Reject::Permanent(text) => multiline(permanent, &lines(text)).into(),
+            // A code that is not a failure would tell the client the request was accepted
+            Reject::Reply(code, text) if (400..600).contains(code) => {
+                multiline(*code, &lines(text)).into()
+            }
+            Reject::Reply(_, text) => multiline(permanent, &lines(text)).into(),
+        }
+    }
+}
+
+/**
+## MessageHandler trait
+   The `MessageHandler` trait decides whether a received mail is accepted, before the client
+   is answered. The client is only told the mail was delivered once the handler accepts it,
+   e.g. when the storage has durably committed the message:
+   - `Ok` replies with `250`, `Accept::NotLocal` included, and the mail is then forwarded to
+     the mail channel.
+   - `Err(Reject::Temporary)` replies with `451`, the client will try again later.
+   - `Err(Reject::Permanent)` replies with `554`, the client will bounce the mail.
+
+   ```rust
+   use async_trait::async_trait;
+   use minismtp::{
+       connection::Mail,
+       hooks::{Accept, MessageHandler, Reject},
+   };
+
+   #[derive(Debug)]
+   struct Storage;
+
+   #[async_trait]
+   impl MessageHandler for Storage {
+       async fn on_message(&self, mail: &Mail) -> Result<Accept, Reject> {
+           match std::fs::write(format!("/tmp/{}.eml", mail.queue_id), &mail.data) {
+               Ok(()) => Ok(Accept::Reply(format!("Queued as {}", mail.queue_id))),
+               Err(_) => Err(Reject::Temporary("Storage unavailable".to_string())),
+           }
+       }
+   }
+   ```
+*/
+#[async_trait]
+pub trait MessageHandler: Debug + Send + Sync {
+    async fn on_message(&self, mail: &Mail) -> Result<Accept, Reject>;
+}
+
+/**
+## RecipientPolicy trait
+   The `RecipientPolicy` trait decides whether each recipient of a RCPT TO command is accepted,
+   e.g. to reject unknown users instead of bouncing their mail later. It receives the recipient
+   and the mail transaction so far, and only accepted recipients are added to `Mail::to`:
+   - `Ok` replies with `250`, or `251` for a recipient that is not local.
+   - `Err(Reject::Temporary)` replies with `450`, the client may try again later.
+   - `Err(Reject::Permanent)` replies with `550`, e.g. `550 5.1.1 No such user`.
+
+   ```rust
+   use async_trait::async_trait;
+   use minismtp::{
+       connection::Mail,
+       hooks::{Accept, RecipientPolicy, Reject},
+   };
+
+   #[derive(Debug)]
+   struct LocalUsers;
+
+   #[async_trait]
+   impl RecipientPolicy for LocalUsers {
+       async fn check_recipient(&self, recipient: &str, mail: &Mail) -> Result<Accept, Reject> {
+           if mail.to.len() >= 100 {
+               return Err(Reject::Reply(452, "Too many recipients".to_string()));
+           }
+           match recipient {
+               "root@localhost" | "postmaster" => Ok(Accept::Ok),
+               _ => Err(Reject::Permanent("5.1.1 No such user".to_string())),
+           }
+       }
+   }
+   ```
+*/
+#[async_trait]
+pub trait RecipientPolicy: Debug + Send + Sync {
+    async fn check_recipient(&self, recipient: &str, mail: &Mail) -> Result<Accept, Reject>;
+}
+
+/**
+## SenderPolicy trait
+   The `SenderPolicy` trait decides whether the reverse-path of a MAIL FROM command is accepted,
+   e.g. to block known bad senders or to make authenticated users send as their own addresses.
+   It receives the transaction that would start, with the sender, the name the client greeted
+   with, its address and the identity it authenticated as, and the ESMTP parameters of the command:
+   - `Ok` replies with `250` and starts the transaction, `Accept::NotLocal` included.
+   - `Err(Reject::Temporary)` replies with `451`, the client may try again later.
+   - `Err(Reject::Permanent)` replies with `550`.
+
+   ```rust
+   use async_trait::async_trait;
+   use minismtp::{
+       connection::Mail,
+       hooks::{Accept, Reject, SenderPolicy},
+   };
+
+   #[derive(Debug)]
+   struct OwnAddress;
+
+   #[async_trait]
+   impl SenderPolicy for OwnAddress {
+       async fn check_sender(&self, mail: &Mail, _parameters: &[String]) -> Result<Accept, Reject> {
+           match &mail.authenticated {
+               Some(user) if mail.from != format!("{}@example.com", user) => Err(
+                   Reject::Reply(553, "5.7.1 Sender address not owned by user".to_string()),
+               ),
+               _ => Ok(Accept::Ok),
+           }
+       }
+   }
+   ```
+*/
+#[async_trait]
+pub trait SenderPolicy: Debug + Send + Sync {
+    async fn check_sender(&self, mail: &Mail, parameters: &[String]) -> Result<Accept, Reject>;
+}
+
+/**
+## ConnectionPolicy trait
+   The `ConnectionPolicy` trait decides whether a connection is served, right after it is
+   accepted and before the client is greeted, e.g. with IP reputation or firewall rules.
+   It receives the address of the client and the local address it connected to:
+   - `Ok` greets the client, right away or after a delay.
+   - `Err(Reject::Temporary)` replies with `421` and closes the connection.
+   - `Err(Reject::Permanent)` replies with `554`, e.g. `554 No SMTP service here`, and
+     closes the connection.
+
+   ```rust
+   use std::net::SocketAddr;
+
+   use async_trait::async_trait;
+   use minismtp::hooks::{Admit, ConnectionPolicy, Reject};
+
+   #[derive(Debug)]
+   struct Loopback;
+
+   #[async_trait]
+   impl ConnectionPolicy for Loopback {
+       async fn check_connection(
+           &self,
+           remote_addr: SocketAddr,
+           _local_addr: SocketAddr,
+       ) -> Result<Admit, Reject> {
+           match remote_addr.ip().is_loopback() {
+               true => Ok(Admit::Greet),
+               false => Err(Reject::Permanent("No SMTP service here".to_string())),
+           }
+       }
+   }
+   ```
+*/
+#[async_trait]
+pub trait ConnectionPolicy: Debug + Send + Sync {
+    async fn check_connection(
+        &self,
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> Result<Admit, Reject>;
+}
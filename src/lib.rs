@@ -1,4 +1,16 @@
+/**
+Contains the authentication of clients with the AUTH command.
+*/
+pub mod auth;
 pub mod connection;
+/**
+Contains the lookup of connecting clients on DNS blocklists.
+*/
+pub mod dnsbl;
+/**
+Contains the hooks with which the server asks the application whether to accept mail.
+*/
+pub mod hooks;
 mod parser;
 
 /**
@@ -8,15 +20,65 @@ pub mod server;
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, UNIX_EPOCH};
 
-    use crate::server::SmtpServer;
+    use crate::auth::{
+        Authenticator, CredentialStore, ScramCredentials, TokenVerifier, OAUTHBEARER_ERROR,
+        XOAUTH2_ERROR,
+    };
+    use crate::connection::{BodyType, ClientCertificate, Mail};
+    use crate::dnsbl::{Dnsbl, DnsblAction, DnsblListing, DnsblZone};
+    use crate::hooks::{
+        Accept, Admit, ConnectionPolicy, MessageHandler, RecipientPolicy, Reject, SenderPolicy,
+    };
+    use crate::server::{ServerError, SmtpServer};
     use async_smtp::{Envelope, SendableEmail, SmtpClient, SmtpTransport};
+    use async_trait::async_trait;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+    use hickory_resolver::proto::{
+        op::{Message as DnsMessage, MessageType, OpCode, ResponseCode},
+        rr::{rdata::A, RData, Record},
+    };
+    use ipnetwork::IpNetwork;
     use lettre::message::header::ContentType;
+    use lettre::transport::smtp::authentication::Credentials;
     use lettre::transport::smtp::client::{Tls, TlsParameters};
     use lettre::{Message, Transport};
-    use tokio::{io::BufStream, net::TcpStream};
+    use mail_parser::{MessageParser, Protocol};
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        sign::Signer,
+        x509::{
+            extension::{BasicConstraints, SubjectAlternativeName},
+            X509NameBuilder, X509,
+        },
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
+        net::{TcpStream, UdpSocket},
+    };
+    use tokio_rustls::{
+        client::TlsStream,
+        rustls::{
+            self,
+            client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            crypto::aws_lc_rs,
+            pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+            ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+        },
+        TlsConnector,
+    };
 
     async fn send_email_async_smtp() {
         let stream = BufStream::new(TcpStream::connect("localhost:2525").await.unwrap());
@@ -59,9 +121,168 @@ mod tests {
         drop(mailer);
     }
 
+    async fn read_reply(stream: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut buf = vec![0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    async fn send_command(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        command: &str,
+    ) -> String {
+        stream.write_all(command.as_bytes()).await.unwrap();
+        read_reply(stream).await
+    }
+
+    /// Accepts the self-signed test certificate
+    #[derive(Debug)]
+    struct NoVerifier;
+
+    impl ServerCertVerifier for NoVerifier {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            aws_lc_rs::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    async fn tls_connect(stream: TcpStream, server_name: &str) -> TlsStream<TcpStream> {
+        tls_connect_with(stream, server_name, None).await.unwrap()
+    }
+
+    async fn tls_connect_with(
+        stream: TcpStream,
+        server_name: &str,
+        client_certificate: Option<&(X509, PKey<Private>)>,
+    ) -> std::io::Result<TlsStream<TcpStream>> {
+        let config = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier));
+        let config = match client_certificate {
+            Some((cert, key)) => {
+                let cert = CertificateDer::from(cert.to_der().unwrap());
+                let key = PrivateKeyDer::try_from(key.private_key_to_pkcs8().unwrap()).unwrap();
+                config.with_client_auth_cert(vec![cert], key).unwrap()
+            }
+            None => config.with_no_client_auth(),
+        };
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+    }
+
+    /// Builds a certificate for the given name, signed by the issuer or self-signed
+    fn build_certificate(
+        name: &str,
+        issuer: Option<&(X509, PKey<Private>)>,
+        ca: bool,
+    ) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        let (issuer_name, issuer_key) = match issuer {
+            Some((cert, key)) => (cert.subject_name(), key),
+            None => (subject.as_ref(), &key),
+        };
+        builder.set_issuer_name(issuer_name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if ca {
+            let constraints = BasicConstraints::new().critical().ca().build().unwrap();
+            builder.append_extension(constraints).unwrap();
+        } else {
+            let context = builder.x509v3_context(issuer.map(|(cert, _)| cert.as_ref()), None);
+            let san = SubjectAlternativeName::new()
+                .dns(name)
+                .build(&context)
+                .unwrap();
+            builder.append_extension(san).unwrap();
+        }
+        builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    /// Generates a self-signed certificate for the given name, returning the certificate and key
+    fn generate_certificate(name: &str) -> (Vec<u8>, Vec<u8>) {
+        let (cert, key) = build_certificate(name, None, false);
+        (
+            cert.to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    /// The certificate presented by the server
+    fn peer_certificate(stream: &TlsStream<TcpStream>) -> Vec<u8> {
+        let certificates = stream.get_ref().1.peer_certificates().unwrap();
+        certificates[0].to_vec()
+    }
+
+    fn der(cert_pem: &[u8]) -> Vec<u8> {
+        X509::from_pem(cert_pem).unwrap().to_der().unwrap()
+    }
+
+    async fn starttls(port: u16) -> TlsStream<TcpStream> {
+        starttls_as(port, "localhost").await
+    }
+
+    async fn starttls_as(port: u16, server_name: &str) -> TlsStream<TcpStream> {
+        let mut stream = TcpStream::connect(("localhost", port)).await.unwrap();
+        read_reply(&mut stream).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+        let reply = send_command(&mut stream, "STARTTLS\r\n").await;
+        assert!(reply.starts_with("220"));
+        tls_connect(stream, server_name).await
+    }
+
     #[tokio::test]
     async fn test() {
-        env_logger::builder().is_test(true).try_init().unwrap();
+        let _ = env_logger::builder().is_test(true).try_init();
 
         let server = SmtpServer::new(
             "localhost".to_string(),
@@ -81,10 +302,1767 @@ mod tests {
 
         log::info!("Sending via lettre");
 
-        thread::spawn(|| send_email_lettre());
+        thread::spawn(send_email_lettre);
         let mail = listening_server.mail_rx.recv().await.unwrap();
         log::info!("Received mail: {:?}", mail);
         listening_server.stop().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_multiple_transactions() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2526,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        );
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2526").await.unwrap();
+        assert!(read_reply(&mut stream).await.starts_with("220"));
+        assert!(send_command(&mut stream, "EHLO client\r\n")
+            .await
+            .starts_with("250"));
+
+        // An aborted transaction is never forwarded
+        send_command(&mut stream, "MAIL FROM:<aborted@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        assert_eq!(send_command(&mut stream, "RSET\r\n").await, "250 OK\r\n");
+
+        let mut queue_ids = Vec::new();
+        let mut session_ids = Vec::new();
+        for sender in ["first@localhost", "second@localhost"] {
+            let mail_from = format!("MAIL FROM:<{}>\r\n", sender);
+            assert_eq!(send_command(&mut stream, &mail_from).await, "250 OK\r\n");
+            assert_eq!(
+                send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await,
+                "250 OK\r\n"
+            );
+            assert!(send_command(&mut stream, "DATA\r\n")
+                .await
+                .starts_with("354"));
+            assert_eq!(
+                send_command(&mut stream, "Hello world\r\n.\r\n").await,
+                "250 OK\r\n"
+            );
+
+            // Every mail is forwarded as soon as its data has been received
+            let mail = listening_server.mail_rx.recv().await.unwrap();
+            assert_eq!(mail.from, sender);
+            assert_eq!(mail.to, vec!["root@localhost".to_string()]);
+            assert_eq!(mail.tls, None);
+
+            // Every mail of the connection carries the same session and its own queue id
+            assert_eq!(mail.session.remote_addr, Some(stream.local_addr().unwrap()));
+            assert_eq!(mail.session.local_addr, Some(stream.peer_addr().unwrap()));
+            assert_eq!(mail.session.helo.as_deref(), Some("client"));
+            assert!(mail.session.esmtp);
+            assert!(mail.received_at.unwrap() >= mail.session.connected_at);
+            assert!(!queue_ids.contains(&mail.queue_id));
+            queue_ids.push(mail.queue_id);
+            session_ids.push(mail.session.id);
+        }
+        assert_eq!(session_ids[0], session_ids[1]);
+
+        assert!(send_command(&mut stream, "QUIT\r\n")
+            .await
+            .starts_with("221"));
+
+        // A client greeting with HELO is recorded as well, without service extensions
+        let mut stream = TcpStream::connect("localhost:2526").await.unwrap();
+        read_reply(&mut stream).await;
+        assert!(send_command(&mut stream, "HELO relay\r\n")
+            .await
+            .starts_with("250"));
+        send_command(&mut stream, "MAIL FROM:<relay@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Hello world\r\n.\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.session.helo.as_deref(), Some("relay"));
+        assert!(!mail.session.esmtp);
+        assert_ne!(mail.session.id, session_ids[0]);
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reply_codes() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2527,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        );
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2527").await.unwrap();
+        read_reply(&mut stream).await;
+
+        // Every error is answered and the session stays alive
+        let replies = [
+            ("MAIL FROM:<user@localhost>\r\n", "503"),
+            ("EHLO\r\n", "501"),
+            ("EHLO client\r\n", "250"),
+            ("UNKNOWN\r\n", "500"),
+            ("VRFY root\r\n", "502"),
+            ("RCPT TO:<root@localhost>\r\n", "503"),
+            ("DATA\r\n", "503"),
+            ("STARTTLS\r\n", "502"),
+            ("MAIL <user@localhost>\r\n", "501"),
+            ("MAIL FROM:<user>\r\n", "553"),
+            ("MAIL FROM:<user@localhost> UNKNOWN=1\r\n", "555"),
+            ("MAIL FROM:<>\r\n", "250"),
+            ("DATA\r\n", "503"),
+            ("RCPT TO:root@localhost\r\n", "501"),
+            ("RCPT TO:<root>\r\n", "553"),
+            ("RCPT TO: <Postmaster>\r\n", "250"),
+            ("NOOP\r\n", "250"),
+            ("DATA\r\n", "354"),
+        ];
+        for (command, code) in replies {
+            let reply = send_command(&mut stream, command).await;
+            assert!(reply.starts_with(code), "{:?} => {:?}", command, reply);
+        }
+
+        send_command(&mut stream, "Hello world\r\n.\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.from, "");
+        assert_eq!(mail.to, vec!["Postmaster".to_string()]);
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_line_framing() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2528,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            Some(64),
+            None,
+            None,
+        );
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2528").await.unwrap();
+        read_reply(&mut stream).await;
+
+        // A command split across several writes
+        stream.write_all(b"EH").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(send_command(&mut stream, "LO client\r\n")
+            .await
+            .starts_with("250"));
+
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+
+        // Dot-stuffed lines and a terminator split across writes
+        for chunk in ["..hidden\r\nline\r", "\n.", "\r\n"] {
+            stream.write_all(chunk.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(read_reply(&mut stream).await, "250 OK\r\n");
+
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.data, b".hidden\r\nline\r\n");
+
+        // A line longer than the buffer is answered and skipped, even when split across writes
+        stream.write_all(&[b'X'; 100]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let reply = send_command(&mut stream, &format!("{}\r\nNOOP\r\n", "X".repeat(100))).await;
+        assert_eq!(reply, "500 Line too long\r\n250 OK\r\n");
+
+        // Within the mail data, the mail is rejected once its end is received
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let reply = send_command(&mut stream, &format!("{}\r\n.\r\n", "X".repeat(100))).await;
+        assert!(reply.starts_with("552"));
+
+        // Further over-long lines of a discarded mail are not answered either
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let long_line = format!("{}\r\n", "X".repeat(100));
+        let reply = send_command(&mut stream, &format!("{}{}.\r\n", long_line, long_line)).await;
+        assert_eq!(reply, "552 Message exceeds fixed maximum message size\r\n");
+        assert_eq!(send_command(&mut stream, "NOOP\r\n").await, "250 OK\r\n");
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2529,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        );
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2529").await.unwrap();
+        read_reply(&mut stream).await;
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(ehlo.contains("PIPELINING\r\n"));
+
+        // The whole batch is answered in order with a single write
+        let replies = send_command(
+            &mut stream,
+            "MAIL FROM:<user@localhost>\r\nRCPT TO:<root@localhost>\r\nRCPT TO:<root>\r\nDATA\r\n",
+        )
+        .await;
+        assert_eq!(
+            replies,
+            "250 OK\r\n250 OK\r\n553 Requested action not taken: mailbox name not allowed\r\n\
+             354 Start mail input; end with <CRLF>.<CRLF>\r\n"
+        );
+
+        let replies = send_command(&mut stream, "Hello world\r\n.\r\nQUIT\r\n").await;
+        assert_eq!(replies, "250 OK\r\n221 Bye\r\n");
+
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.to, vec!["root@localhost".to_string()]);
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_message_size() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2530,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        )
+        .max_message_size(16);
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2530").await.unwrap();
+        read_reply(&mut stream).await;
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(ehlo.contains("SIZE 16\r\n"));
+
+        // A message declared too large is rejected before any data is sent
+        let reply = send_command(&mut stream, "MAIL FROM:<user@localhost> SIZE=17\r\n").await;
+        assert!(reply.starts_with("552"));
+
+        // A message exceeding the limit during DATA is discarded
+        send_command(&mut stream, "MAIL FROM:<user@localhost> SIZE=16\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let reply = send_command(&mut stream, "Hello\r\nworld, this is too long\r\n.\r\n").await;
+        assert!(reply.starts_with("552"));
+
+        // The session goes on and accepts a message within the limit
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let reply = send_command(&mut stream, "Hello world\r\n.\r\n").await;
+        assert_eq!(reply, "250 OK\r\n");
+
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.data, b"Hello world\r\n");
+
+        // A chunk larger than any message is refused without reading it, closing the connection
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        let reply = send_command(&mut stream, "BDAT 1\r\nX").await;
+        assert_eq!(reply, "250 1 octets received\r\n");
+        let reply = send_command(&mut stream, "BDAT 18446744073709551615 LAST\r\n").await;
+        assert!(reply.starts_with("552"));
+        assert_eq!(read_reply(&mut stream).await, "");
+
+        // Out of sequence, it is only answered with the reason it is out of sequence
+        let mut stream = TcpStream::connect("localhost:2530").await.unwrap();
+        read_reply(&mut stream).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+        let reply = send_command(&mut stream, "BDAT 17 LAST\r\n").await;
+        assert_eq!(reply, "503 Need MAIL command first\r\n");
+        assert_eq!(read_reply(&mut stream).await, "");
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_smtputf8() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2531,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        );
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2531").await.unwrap();
+        read_reply(&mut stream).await;
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(ehlo.contains("8BITMIME\r\n") && ehlo.contains("SMTPUTF8\r\n"));
+
+        // UTF-8 addresses are only accepted once SMTPUTF8 has been requested
+        let reply = send_command(&mut stream, "MAIL FROM:<用户@例子.广告>\r\n").await;
+        assert!(reply.starts_with("553"));
+        let reply = send_command(
+            &mut stream,
+            "MAIL FROM:<用户@例子.广告> BODY=8BITMIME SMTPUTF8\r\n",
+        )
+        .await;
+        assert_eq!(reply, "250 OK\r\n");
+        let reply = send_command(&mut stream, "RCPT TO:<pelé@exämple.com>\r\n").await;
+        assert_eq!(reply, "250 OK\r\n");
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Grüße\r\n.\r\n").await;
+
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.from, "用户@例子.广告");
+        assert_eq!(mail.to, vec!["pelé@exämple.com".to_string()]);
+        assert_eq!(mail.body, BodyType::EightBitMime);
+        assert!(mail.smtputf8);
+        assert_eq!(mail.data, "Grüße\r\n".as_bytes());
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunking() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2532,
+            "localhost".to_string(),
+            Some(Duration::from_secs(1)),
+            None,
+            None,
+            None,
+        );
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2532").await.unwrap();
+        read_reply(&mut stream).await;
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(ehlo.contains("CHUNKING\r\n") && ehlo.contains("BINARYMIME\r\n"));
+
+        // A rejected chunk is skipped instead of being read as commands
+        let reply = send_command(&mut stream, "BDAT 6 LAST\r\nQUIT\r\n").await;
+        assert!(reply.starts_with("503"));
+        assert_eq!(send_command(&mut stream, "NOOP\r\n").await, "250 OK\r\n");
+
+        send_command(
+            &mut stream,
+            "MAIL FROM:<user@localhost> BODY=BINARYMIME\r\n",
+        )
+        .await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        assert!(send_command(&mut stream, "DATA\r\n")
+            .await
+            .starts_with("503"));
+
+        // Chunks are stored as is, without looking for the end of data marker
+        stream.write_all(b"BDAT 9\r\nline\r\n.\r\n").await.unwrap();
+        assert_eq!(read_reply(&mut stream).await, "250 9 octets received\r\n");
+        stream.write_all(b"BDAT 2 LAST\r\n\x00\xff").await.unwrap();
+        assert_eq!(read_reply(&mut stream).await, "250 OK\r\n");
+
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.data, b"line\r\n.\r\n\x00\xff");
+        assert_eq!(mail.body, BodyType::BinaryMime);
+
+        // A chunk may take longer than the timeout, as long as its data keeps arriving
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        stream.write_all(b"BDAT 40 LAST\r\n").await.unwrap();
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            stream.write_all(&[b'X'; 10]).await.unwrap();
+        }
+        assert_eq!(read_reply(&mut stream).await, "250 OK\r\n");
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.data, [b'X'; 40]);
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct TestAuthenticator;
+
+    #[async_trait]
+    impl Authenticator for TestAuthenticator {
+        async fn validate(&self, username: &str, password: &str) -> bool {
+            username == "user" && password == "secret"
+        }
+    }
+
+    fn send_email_lettre_authenticated(password: &str) -> bool {
+        let email = Message::builder()
+            .from("NoBody <nobody@domain.tld>".parse().unwrap())
+            .to("Hei <hei@domain.tld>".parse().unwrap())
+            .subject("Authenticated")
+            .body(String::from("Be happy!"))
+            .unwrap();
+        let tls_parameters = TlsParameters::builder("localhost".to_string())
+            .dangerous_accept_invalid_certs(true)
+            .dangerous_accept_invalid_hostnames(true)
+            .build()
+            .unwrap();
+        let mailer = lettre::SmtpTransport::builder_dangerous("localhost")
+            .tls(Tls::Required(tls_parameters))
+            .port(2533)
+            .credentials(Credentials::new("user".to_string(), password.to_string()))
+            .build();
+
+        mailer.send(&email).is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2533,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            Some("cert.pem".into()),
+            Some("key.pem".into()),
+        )
+        .authenticator(TestAuthenticator)
+        .require_auth(true);
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2533").await.unwrap();
+        read_reply(&mut stream).await;
+
+        // PLAIN and LOGIN are not offered before the connection is encrypted
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(!ehlo.contains("AUTH"));
+        let reply = send_command(&mut stream, "AUTH PLAIN AHVzZXIAc2VjcmV0\r\n").await;
+        assert!(reply.starts_with("538"));
+        let reply = send_command(&mut stream, "AUTH UNKNOWN\r\n").await;
+        assert!(reply.starts_with("504"));
+        let reply = send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        assert!(reply.starts_with("530"));
+        send_command(&mut stream, "QUIT\r\n").await;
+
+        // Invalid credentials are rejected, valid ones are recorded on the mail
+        assert!(
+            !tokio::task::spawn_blocking(|| send_email_lettre_authenticated("wrong"))
+                .await
+                .unwrap()
+        );
+        assert!(
+            tokio::task::spawn_blocking(|| send_email_lettre_authenticated("secret"))
+                .await
+                .unwrap()
+        );
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.authenticated.as_deref(), Some("user"));
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct TestCredentialStore;
+
+    #[async_trait]
+    impl CredentialStore for TestCredentialStore {
+        async fn scram_credentials(&self, username: &str) -> Option<ScramCredentials> {
+            (username == "user").then(|| ScramCredentials::new("secret", b"salt", 4096).unwrap())
+        }
+
+        async fn password(&self, username: &str) -> Option<String> {
+            (username == "user").then(|| "secret".to_string())
+        }
+
+        fn provides_passwords(&self) -> bool {
+            true
+        }
+    }
+
+    fn hmac(digest: MessageDigest, key: &[u8], data: &[u8]) -> Vec<u8> {
+        let key = PKey::hmac(key).unwrap();
+        let mut signer = Signer::new(digest, &key).unwrap();
+        signer.update(data).unwrap();
+        signer.sign_to_vec().unwrap()
+    }
+
+    async fn send_sasl(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        response: &str,
+    ) -> String {
+        send_command(stream, &format!("{}\r\n", STANDARD.encode(response))).await
+    }
+
+    fn decode_challenge(reply: &str) -> String {
+        let challenge = reply.strip_prefix("334 ").unwrap().trim_end();
+        String::from_utf8(STANDARD.decode(challenge).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_challenge_response_auth() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2534,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        )
+        .credential_store(TestCredentialStore);
+
+        let listening_server = server.start().await.unwrap();
+
+        // CRAM-MD5, the client replies with the HMAC-MD5 of the challenge
+        let mut stream = TcpStream::connect("localhost:2534").await.unwrap();
+        read_reply(&mut stream).await;
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(ehlo.contains("AUTH SCRAM-SHA-256 CRAM-MD5\r\n"));
+        assert!(send_command(&mut stream, "AUTH PLAIN\r\n")
+            .await
+            .starts_with("538"));
+
+        for (password, code) in [("wrong", "535"), ("secret", "235")] {
+            let reply = send_command(&mut stream, "AUTH CRAM-MD5\r\n").await;
+            let challenge = decode_challenge(&reply);
+            let digest = hmac(
+                MessageDigest::md5(),
+                password.as_bytes(),
+                challenge.as_bytes(),
+            );
+            let digest = digest
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            let reply = send_sasl(&mut stream, &format!("user {}", digest)).await;
+            assert!(reply.starts_with(code));
+        }
+        assert!(send_command(&mut stream, "AUTH CRAM-MD5\r\n")
+            .await
+            .starts_with("503"));
+        send_command(&mut stream, "QUIT\r\n").await;
+
+        // SCRAM-SHA-256, both sides prove that they know the salted password
+        let mut stream = TcpStream::connect("localhost:2534").await.unwrap();
+        read_reply(&mut stream).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+
+        // An unknown user is only rejected at the final step, so users cannot be enumerated
+        let reply = send_command(
+            &mut stream,
+            &format!(
+                "AUTH SCRAM-SHA-256 {}\r\n",
+                STANDARD.encode("n,,n=nobody,r=rOprNGfwEbeRWgbNEkqO")
+            ),
+        )
+        .await;
+        let server_first = decode_challenge(&reply);
+        assert!(server_first.ends_with(",i=4096"));
+        let nonce = server_first
+            .strip_prefix("r=")
+            .and_then(|rest| rest.split(',').next())
+            .unwrap();
+        let reply = send_sasl(
+            &mut stream,
+            &format!("c=biws,r={},p={}", nonce, STANDARD.encode([0; 32])),
+        )
+        .await;
+        assert!(reply.starts_with("535"));
+
+        let client_first_bare = "n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let reply = send_command(
+            &mut stream,
+            &format!(
+                "AUTH SCRAM-SHA-256 {}\r\n",
+                STANDARD.encode(format!("n,,{}", client_first_bare))
+            ),
+        )
+        .await;
+        let server_first = decode_challenge(&reply);
+        let nonce = server_first
+            .strip_prefix("r=")
+            .and_then(|rest| rest.split(',').next())
+            .unwrap();
+        assert!(nonce.starts_with("rOprNGfwEbeRWgbNEkqO"));
+        assert!(server_first.ends_with(&format!(",s={},i=4096", STANDARD.encode("salt"))));
+
+        let credentials = ScramCredentials::new("secret", b"salt", 4096).unwrap();
+        let mut salted_password = [0; 32];
+        openssl::pkcs5::pbkdf2_hmac(
+            b"secret",
+            b"salt",
+            4096,
+            MessageDigest::sha256(),
+            &mut salted_password,
+        )
+        .unwrap();
+        let client_key = hmac(MessageDigest::sha256(), &salted_password, b"Client Key");
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_signature = hmac(
+            MessageDigest::sha256(),
+            &credentials.stored_key,
+            auth_message.as_bytes(),
+        );
+        let proof = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+        let reply = send_sasl(
+            &mut stream,
+            &format!("{},p={}", without_proof, STANDARD.encode(proof)),
+        )
+        .await;
+        let server_signature = hmac(
+            MessageDigest::sha256(),
+            &credentials.server_key,
+            auth_message.as_bytes(),
+        );
+        assert_eq!(
+            decode_challenge(&reply),
+            format!("v={}", STANDARD.encode(server_signature))
+        );
+        assert!(send_command(&mut stream, "\r\n").await.starts_with("235"));
+
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Hello\r\n.\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.authenticated.as_deref(), Some("user"));
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct ScramOnlyCredentialStore;
+
+    #[async_trait]
+    impl CredentialStore for ScramOnlyCredentialStore {
+        async fn scram_credentials(&self, username: &str) -> Option<ScramCredentials> {
+            (username == "user").then(|| ScramCredentials::new("secret", b"salt", 4096).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scram_only_credential_store() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2554,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        )
+        .credential_store(ScramOnlyCredentialStore);
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2554").await.unwrap();
+        read_reply(&mut stream).await;
+
+        // CRAM-MD5 is not offered, since the store cannot provide passwords
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(ehlo.contains("AUTH SCRAM-SHA-256\r\n"));
+        assert!(send_command(&mut stream, "AUTH CRAM-MD5\r\n")
+            .await
+            .starts_with("504"));
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct TestTokenVerifier;
+
+    #[async_trait]
+    impl TokenVerifier for TestTokenVerifier {
+        async fn verify(&self, username: Option<&str>, token: &str) -> Option<String> {
+            (token == "valid-token").then(|| username.unwrap_or("service").to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_auth() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2535,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            Some("cert.pem".into()),
+            Some("key.pem".into()),
+        )
+        .token_verifier(TestTokenVerifier);
+
+        let listening_server = server.start().await.unwrap();
+
+        // Bearer tokens are only accepted over TLS
+        let mut stream = TcpStream::connect("localhost:2535").await.unwrap();
+        read_reply(&mut stream).await;
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(!ehlo.contains("AUTH"));
+        assert!(send_command(&mut stream, "AUTH XOAUTH2\r\n")
+            .await
+            .starts_with("538"));
+        send_command(&mut stream, "QUIT\r\n").await;
+
+        let mut stream = starttls(2535).await;
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(ehlo.contains("AUTH OAUTHBEARER XOAUTH2\r\n"));
+
+        // A rejected token gets the error challenge, then 535 once the client acknowledges it
+        let response = STANDARD.encode("n,a=user,\x01auth=Bearer expired\x01\x01");
+        let reply = send_command(&mut stream, &format!("AUTH OAUTHBEARER {}\r\n", response)).await;
+        assert_eq!(decode_challenge(&reply), OAUTHBEARER_ERROR);
+        assert!(send_sasl(&mut stream, "\x01").await.starts_with("535"));
+
+        let reply = send_command(&mut stream, "AUTH XOAUTH2\r\n").await;
+        assert_eq!(reply, "334 \r\n");
+        let reply = send_sasl(&mut stream, "user=user\x01auth=Bearer expired\x01\x01").await;
+        assert_eq!(decode_challenge(&reply), XOAUTH2_ERROR);
+        assert!(send_command(&mut stream, "\r\n").await.starts_with("535"));
+
+        // A malformed response is rejected without asking the verifier
+        let reply = send_command(&mut stream, "AUTH OAUTHBEARER\r\n").await;
+        assert_eq!(reply, "334 \r\n");
+        assert!(send_sasl(
+            &mut stream,
+            "p=tls-unique,,\x01auth=Bearer valid-token\x01\x01"
+        )
+        .await
+        .starts_with("501"));
+
+        let response =
+            STANDARD.encode("n,a=user,\x01host=localhost\x01auth=Bearer valid-token\x01\x01");
+        let reply = send_command(&mut stream, &format!("AUTH OAUTHBEARER {}\r\n", response)).await;
+        assert!(reply.starts_with("235"));
+
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Hello\r\n.\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.authenticated.as_deref(), Some("user"));
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_implicit_tls() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2536,
+            "localhost".to_string(),
+            Some(Duration::from_secs(2)),
+            None,
+            Some("cert.pem".into()),
+            Some("key.pem".into()),
+        )
+        .implicit_tls(true);
+
+        // Without certificates, no connection could ever be served
+        let without_tls = SmtpServer::new(
+            "localhost".to_string(),
+            2536,
+            "localhost".to_string(),
+            None,
+            None,
+            None,
+            None,
+        )
+        .implicit_tls(true);
+        assert!(matches!(without_tls.start().await, Err(ServerError::NoTls)));
+
+        let listening_server = server.start().await.unwrap();
+
+        // The handshake comes first, so the greeting is already encrypted
+        let stream = TcpStream::connect("localhost:2536").await.unwrap();
+        let mut stream = tls_connect(stream, "localhost").await;
+        assert!(read_reply(&mut stream).await.starts_with("220"));
+
+        let ehlo = send_command(&mut stream, "EHLO client\r\n").await;
+        assert!(!ehlo.contains("STARTTLS"));
+        assert!(send_command(&mut stream, "STARTTLS\r\n")
+            .await
+            .starts_with("503"));
+
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let reply = send_command(&mut stream, "Hello\r\n.\r\n").await;
+        assert_eq!(reply, "250 OK\r\n");
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.data, b"Hello\r\n");
+        let tls = mail.tls.unwrap();
+        assert_eq!(tls.version, "TLSv1.3");
+        assert!(tls.cipher_suite.starts_with("TLS13_"));
+        assert_eq!(tls.sni.as_deref(), Some("localhost"));
+
+        // A client that never starts the handshake is disconnected once the timeout expires
+        let mut stream = TcpStream::connect("localhost:2536").await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), read_reply(&mut stream)).await;
+        assert_eq!(read.unwrap(), "");
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_require_tls() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2537,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            Some("cert.pem".into()),
+            Some("key.pem".into()),
+        )
+        .authenticator(TestAuthenticator)
+        .require_tls(true);
+
+        // Without certificates, no mail could ever be accepted
+        let without_tls = SmtpServer::new(
+            "localhost".to_string(),
+            2537,
+            "localhost".to_string(),
+            None,
+            None,
+            None,
+            None,
+        )
+        .require_tls(true);
+        assert!(matches!(without_tls.start().await, Err(ServerError::NoTls)));
+
+        let listening_server = server.start().await.unwrap();
+
+        let mut stream = TcpStream::connect("localhost:2537").await.unwrap();
+        read_reply(&mut stream).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+        for command in [
+            "MAIL FROM:<user@localhost>\r\n",
+            "RCPT TO:<root@localhost>\r\n",
+            "AUTH PLAIN\r\n",
+        ] {
+            let reply = send_command(&mut stream, command).await;
+            assert_eq!(reply, "530 Must issue a STARTTLS command first\r\n");
+        }
+        assert_eq!(send_command(&mut stream, "NOOP\r\n").await, "250 OK\r\n");
+        send_command(&mut stream, "QUIT\r\n").await;
+
+        // Once encrypted, the transaction proceeds as usual
+        let mut stream = starttls(2537).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+        let reply = send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        assert_eq!(reply, "250 OK\r\n");
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Hello\r\n.\r\n").await;
+        listening_server.mail_rx.recv().await.unwrap();
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_pem() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let new_server = || {
+            SmtpServer::new(
+                "localhost".to_string(),
+                2538,
+                "localhost".to_string(),
+                Some(Duration::from_secs(10)),
+                None,
+                None,
+                None,
+            )
+        };
+        assert!(new_server().tls_pem(b"not a certificate", b"").is_err());
+
+        // The certificates are loaded from memory instead of files
+        let cert_pem = std::fs::read("cert.pem").unwrap();
+        let key_pem = std::fs::read("key.pem").unwrap();
+        let server = new_server().tls_pem(&cert_pem, &key_pem).unwrap();
+
+        let listening_server = server.start().await.unwrap();
+
+        // Every connection shares the same configuration
+        for _ in 0..2 {
+            let mut stream = starttls(2538).await;
+            send_command(&mut stream, "EHLO client\r\n").await;
+            send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+            send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+            send_command(&mut stream, "DATA\r\n").await;
+            let reply = send_command(&mut stream, "Hello\r\n.\r\n").await;
+            assert_eq!(reply, "250 OK\r\n");
+            listening_server.mail_rx.recv().await.unwrap();
+        }
+        listening_server.stop().await.unwrap();
+
+        // A prebuilt configuration cannot be combined with options the server builds one from
+        let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+            .unwrap()
+            .unwrap();
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        let server_config = Arc::new(server_config);
+        let server = new_server()
+            .tls_config(server_config.clone())
+            .watch_certificates(Duration::from_secs(60));
+        assert!(matches!(
+            server.start().await,
+            Err(ServerError::PrebuiltTls("certificate watching"))
+        ));
+
+        // It has no files to reload from, but can be replaced
+        let listening_server = new_server()
+            .tls_config(server_config.clone())
+            .start()
+            .await
+            .unwrap();
+        assert!(matches!(
+            listening_server.reload_tls(),
+            Err(ServerError::PrebuiltTls(_))
+        ));
+        listening_server.replace_tls_config(server_config).unwrap();
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_reload() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let directory = std::env::temp_dir().join("minismtp-test-tls-reload");
+        std::fs::create_dir_all(&directory).unwrap();
+        let (cert_path, key_path) = (directory.join("cert.pem"), directory.join("key.pem"));
+        let write_certificate = |(cert, key): &(Vec<u8>, Vec<u8>)| {
+            std::fs::write(&cert_path, cert).unwrap();
+            std::fs::write(&key_path, key).unwrap();
+        };
+        let first = generate_certificate("localhost");
+        let second = generate_certificate("localhost");
+        let third = generate_certificate("localhost");
+        write_certificate(&first);
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2539,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            Some(cert_path.clone()),
+            Some(key_path.clone()),
+        )
+        .watch_certificates(Duration::from_millis(50));
+
+        let listening_server = server.start().await.unwrap();
+        let mut established = starttls(2539).await;
+        assert_eq!(peer_certificate(&established), der(&first.0));
+
+        // An explicit reload is used by new handshakes only
+        write_certificate(&second);
+        listening_server.reload_tls().unwrap();
+        let stream = starttls(2539).await;
+        assert_eq!(peer_certificate(&stream), der(&second.0));
+        assert_eq!(
+            send_command(&mut established, "NOOP\r\n").await,
+            "250 OK\r\n"
+        );
+
+        // The watcher picks up changed files by itself
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        write_certificate(&third);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let stream = starttls(2539).await;
+        assert_eq!(peer_certificate(&stream), der(&third.0));
+
+        listening_server.stop().await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sni() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let directory = std::env::temp_dir().join("minismtp-test-sni");
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut certificates = Vec::new();
+        for name in ["localhost", "mail.example.com", "example.org"] {
+            let (cert, key) = generate_certificate(name);
+            let (cert_path, key_path) = (
+                directory.join(format!("{}.cert.pem", name)),
+                directory.join(format!("{}.key.pem", name)),
+            );
+            std::fs::write(&cert_path, &cert).unwrap();
+            std::fs::write(&key_path, &key).unwrap();
+            certificates.push((cert, cert_path, key_path));
+        }
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2540,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            Some(certificates[0].1.clone()),
+            Some(certificates[0].2.clone()),
+        )
+        .sni_certificate(
+            "mail.example.com",
+            certificates[1].1.clone(),
+            certificates[1].2.clone(),
+        )
+        .sni_certificate(
+            "*.example.org",
+            certificates[2].1.clone(),
+            certificates[2].2.clone(),
+        );
+
+        let listening_server = server.start().await.unwrap();
+
+        // The certificate is selected by name, unknown names get the default one
+        for (name, expected) in [
+            ("MAIL.example.com", 1),
+            ("smtp.example.org", 2),
+            ("other.example.net", 0),
+        ] {
+            let mut stream = starttls_as(2540, name).await;
+            assert_eq!(peer_certificate(&stream), der(&certificates[expected].0));
+
+            send_command(&mut stream, "EHLO client\r\n").await;
+            send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+            send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+            send_command(&mut stream, "DATA\r\n").await;
+            send_command(&mut stream, "Hello\r\n.\r\n").await;
+            let mail = listening_server.mail_rx.recv().await.unwrap();
+            assert_eq!(mail.tls.unwrap().sni, Some(name.to_lowercase()));
+        }
+
+        listening_server.stop().await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let directory = std::env::temp_dir().join("minismtp-test-client-certificates");
+        std::fs::create_dir_all(&directory).unwrap();
+        let ca = build_certificate("Test CA", None, true);
+        let ca_path = directory.join("ca.pem");
+        std::fs::write(&ca_path, ca.0.to_pem().unwrap()).unwrap();
+        let relay = build_certificate("relay.internal", Some(&ca), false);
+        let untrusted = build_certificate("relay.internal", None, false);
+
+        let new_server = |port| {
+            SmtpServer::new(
+                "localhost".to_string(),
+                port,
+                "localhost".to_string(),
+                Some(Duration::from_secs(10)),
+                None,
+                Some("cert.pem".into()),
+                Some("key.pem".into()),
+            )
+            .client_ca(ca_path.clone())
+        };
+        let connect = |port, client_certificate| async move {
+            let mut stream = TcpStream::connect(("localhost", port)).await.unwrap();
+            read_reply(&mut stream).await;
+            send_command(&mut stream, "EHLO client\r\n").await;
+            send_command(&mut stream, "STARTTLS\r\n").await;
+            let mut stream = tls_connect_with(stream, "localhost", client_certificate).await?;
+            // With TLS 1.3, a refused certificate is only noticed once the server replies
+            stream.write_all(b"EHLO client\r\n").await?;
+            let mut buf = vec![0; 1024];
+            match stream.read(&mut buf).await? {
+                0 => Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted)),
+                _ => Ok::<_, std::io::Error>(stream),
+            }
+        };
+
+        // A verified certificate counts as authentication and is recorded on the mail
+        let server = new_server(2541)
+            .client_certificate_auth(true)
+            .require_auth(true);
+        let listening_server = server.start().await.unwrap();
+
+        let mut stream = connect(2541, Some(&relay)).await.unwrap();
+        let reply = send_command(&mut stream, "MAIL FROM:<relay@internal>\r\n").await;
+        assert_eq!(reply, "250 OK\r\n");
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Hello\r\n.\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        let expected = ClientCertificate::from_der(&relay.0.to_der().unwrap()).unwrap();
+        assert_eq!(expected.subject, "CN=relay.internal");
+        assert_eq!(mail.authenticated.as_deref(), Some("CN=relay.internal"));
+        assert_eq!(mail.tls.unwrap().client_certificate, Some(expected));
+        let spoofed = build_certificate("relay.internal\0.example", None, true);
+        assert_eq!(
+            ClientCertificate::from_der(&spoofed.0.to_der().unwrap()),
+            None
+        );
+
+        // Without a certificate, the client is accepted but has to authenticate
+        let mut stream = connect(2541, None).await.unwrap();
+        let reply = send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        assert!(reply.starts_with("530"));
+        listening_server.stop().await.unwrap();
+
+        // When a certificate is required, it must be verified with the CA bundle
+        let server = new_server(2542).require_client_certificate(true);
+        let listening_server = server.start().await.unwrap();
+        assert!(connect(2542, None).await.is_err());
+        assert!(connect(2542, Some(&untrusted)).await.is_err());
+        assert!(connect(2542, Some(&relay)).await.is_ok());
+        listening_server.stop().await.unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trace_headers() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2543,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        )
+        .trace_headers(true);
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2543").await.unwrap();
+        read_reply(&mut stream).await;
+
+        let transactions = [
+            ("EHLO client.example\r\n", vec!["root@localhost"]),
+            (
+                "HELO client.example\r\n",
+                vec!["root@localhost", "admin@localhost"],
+            ),
+        ];
+        for (greeting, recipients) in transactions {
+            send_command(&mut stream, greeting).await;
+            send_command(&mut stream, "MAIL FROM:<sender@localhost>\r\n").await;
+            for recipient in &recipients {
+                send_command(&mut stream, &format!("RCPT TO:<{}>\r\n", recipient)).await;
+            }
+            send_command(&mut stream, "DATA\r\n").await;
+            send_command(&mut stream, "Subject: Hello\r\n\r\nHello world\r\n.\r\n").await;
+
+            // The trace headers are prepended to the data as received
+            let mail = listening_server.mail_rx.recv().await.unwrap();
+            assert!(mail
+                .data
+                .starts_with(b"Return-Path: <sender@localhost>\r\nReceived: from client.example"));
+            assert!(mail
+                .data
+                .ends_with(b"\r\nSubject: Hello\r\n\r\nHello world\r\n"));
+
+            let message = MessageParser::default().parse(&mail.data).unwrap();
+            let received = message.received().unwrap();
+            assert_eq!(received.from_ip(), Some("127.0.0.1".parse().unwrap()));
+            assert_eq!(
+                received.by(),
+                Some(&mail_parser::Host::Name("localhost".into()))
+            );
+            assert_eq!(received.id(), Some(mail.queue_id.as_str()));
+            let received_at = mail.received_at.unwrap();
+            let seconds = received_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+            assert_eq!(received.date().unwrap().to_timestamp(), seconds as i64);
+
+            // The protocol tells EHLO from HELO, the recipient is only named if it is the only one
+            match recipients.as_slice() {
+                [recipient] => {
+                    assert_eq!(received.with(), Some(Protocol::ESMTP));
+                    assert_eq!(received.for_(), Some(*recipient));
+                }
+                _ => {
+                    assert_eq!(received.with(), Some(Protocol::SMTP));
+                    assert_eq!(received.for_(), None);
+                }
+            }
+        }
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct TestMessageHandler;
+
+    #[async_trait]
+    impl MessageHandler for TestMessageHandler {
+        async fn on_message(&self, mail: &Mail) -> Result<Accept, Reject> {
+            match mail.to[0].as_str() {
+                "full@localhost" => Err(Reject::Temporary(
+                    "Mailbox full\nTry again later".to_string(),
+                )),
+                "gone@localhost" => Err(Reject::Permanent("Mailbox deleted".to_string())),
+                "remote@localhost" => Ok(Accept::NotLocal("Forwarded".to_string())),
+                "slow@localhost" => {
+                    tokio::time::sleep(Duration::from_millis(1500)).await;
+                    Ok(Accept::Ok)
+                }
+                "stuck@localhost" => {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    Ok(Accept::Ok)
+                }
+                _ => Ok(Accept::Reply(format!("Queued as {}", mail.queue_id))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_handler() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2544,
+            "localhost".to_string(),
+            Some(Duration::from_secs(1)),
+            None,
+            None,
+            None,
+        )
+        .message_handler(TestMessageHandler)
+        .message_handler_timeout(Duration::from_secs(2));
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2544").await.unwrap();
+        read_reply(&mut stream).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+
+        // The reply to the mail data is the decision of the handler
+        let replies = [
+            (
+                "full@localhost",
+                "451-Mailbox full\r\n451 Try again later\r\n",
+            ),
+            ("gone@localhost", "554 Mailbox deleted\r\n"),
+            // The handler is not bound by the timeout of commands, but by its own
+            (
+                "stuck@localhost",
+                "451 Requested action aborted: local error in processing\r\n",
+            ),
+        ];
+        for (recipient, expected) in replies {
+            send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+            send_command(&mut stream, &format!("RCPT TO:<{}>\r\n", recipient)).await;
+            send_command(&mut stream, "DATA\r\n").await;
+            let reply = send_command(&mut stream, "Hello world\r\n.\r\n").await;
+            assert_eq!(reply, expected);
+        }
+        // Rejected mail is not forwarded
+        assert!(listening_server.mail_rx.is_empty());
+
+        // 251 is only a reply to recipients
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<remote@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let reply = send_command(&mut stream, "Hello world\r\n.\r\n").await;
+        assert_eq!(reply, "250 Forwarded\r\n");
+        listening_server.mail_rx.recv().await.unwrap();
+
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<slow@localhost>\r\n").await;
+        send_command(&mut stream, "DATA\r\n").await;
+        let reply = send_command(&mut stream, "Hello world\r\n.\r\n").await;
+        assert_eq!(reply, "250 OK\r\n");
+        listening_server.mail_rx.recv().await.unwrap();
+
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        let reply = send_command(&mut stream, "BDAT 13 LAST\r\nHello world\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(reply, format!("250 Queued as {}\r\n", mail.queue_id));
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct TestRecipientPolicy;
+
+    #[async_trait]
+    impl RecipientPolicy for TestRecipientPolicy {
+        async fn check_recipient(&self, recipient: &str, mail: &Mail) -> Result<Accept, Reject> {
+            if mail.to.len() >= 2 {
+                return Err(Reject::Reply(452, "Too many recipients".to_string()));
+            }
+            match recipient {
+                "root@localhost" => Ok(Accept::Ok),
+                "user@remote.example" => Ok(Accept::NotLocal(
+                    "User not local; will forward to <user@remote.example>".to_string(),
+                )),
+                "busy@localhost" => Err(Reject::Temporary("Mailbox busy".to_string())),
+                _ => Err(Reject::Permanent("5.1.1 No such user".to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recipient_policy() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2545,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        )
+        .recipient_policy(TestRecipientPolicy);
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2545").await.unwrap();
+        read_reply(&mut stream).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+
+        // Every recipient is answered with the decision of the policy
+        let replies = [
+            (
+                "RCPT TO:<unknown@localhost>\r\n",
+                "550 5.1.1 No such user\r\n",
+            ),
+            ("RCPT TO:<busy@localhost>\r\n", "450 Mailbox busy\r\n"),
+            ("RCPT TO:<root@localhost>\r\n", "250 OK\r\n"),
+            (
+                "RCPT TO:<user@remote.example>\r\n",
+                "251 User not local; will forward to <user@remote.example>\r\n",
+            ),
+            (
+                "RCPT TO:<other@localhost>\r\n",
+                "452 Too many recipients\r\n",
+            ),
+        ];
+        for (command, expected) in replies {
+            assert_eq!(send_command(&mut stream, command).await, expected);
+        }
+
+        // Only accepted recipients are kept
+        send_command(&mut stream, "DATA\r\n").await;
+        send_command(&mut stream, "Hello world\r\n.\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.to, vec!["root@localhost", "user@remote.example"]);
+
+        // Pipelined recipients are checked in order and answered together
+        send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        let reply = send_command(
+            &mut stream,
+            "RCPT TO:<unknown@localhost>\r\nRCPT TO:<root@localhost>\r\n",
+        )
+        .await;
+        assert_eq!(reply, "550 5.1.1 No such user\r\n250 OK\r\n");
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct TestSenderPolicy;
+
+    #[async_trait]
+    impl SenderPolicy for TestSenderPolicy {
+        async fn check_sender(&self, mail: &Mail, parameters: &[String]) -> Result<Accept, Reject> {
+            assert!(mail.session.remote_addr.is_some());
+            match (mail.from.as_str(), mail.domain.as_str()) {
+                ("spammer@bad.example", _) => Err(Reject::Permanent("Sender blocked".to_string())),
+                (_, "unknown.client") => Err(Reject::Temporary("Try again later".to_string())),
+                _ if parameters.iter().any(|parameter| parameter == "SIZE=1000") => {
+                    Err(Reject::Reply(552, "Sender over quota".to_string()))
+                }
+                ("user@remote.example", _) => Ok(Accept::NotLocal("Remote sender".to_string())),
+                _ => Ok(Accept::Reply("Sender OK".to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sender_policy() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2546,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        )
+        .sender_policy(TestSenderPolicy);
+
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("localhost:2546").await.unwrap();
+        read_reply(&mut stream).await;
+        send_command(&mut stream, "EHLO client\r\n").await;
+
+        // A rejected sender does not start a transaction
+        let reply = send_command(&mut stream, "MAIL FROM:<spammer@bad.example>\r\n").await;
+        assert_eq!(reply, "550 Sender blocked\r\n");
+        let reply = send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+        assert!(reply.starts_with("503"));
+        let reply = send_command(&mut stream, "MAIL FROM:<user@localhost> SIZE=1000\r\n").await;
+        assert_eq!(reply, "552 Sender over quota\r\n");
+
+        // 251 is only a reply to recipients
+        let reply = send_command(&mut stream, "MAIL FROM:<user@remote.example>\r\n").await;
+        assert_eq!(reply, "250 Remote sender\r\n");
+        send_command(&mut stream, "RSET\r\n").await;
+
+        // The policy sees the parameters and the name the client greeted with
+        let reply = send_command(&mut stream, "MAIL FROM:<user@localhost> SIZE=10\r\n").await;
+        assert_eq!(reply, "250 Sender OK\r\n");
+        send_command(&mut stream, "RSET\r\n").await;
+        send_command(&mut stream, "EHLO unknown.client\r\n").await;
+        let reply = send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+        assert_eq!(reply, "451 Try again later\r\n");
+
+        // Pipelined commands following an accepted sender belong to the transaction
+        send_command(&mut stream, "EHLO client\r\n").await;
+        let reply = send_command(
+            &mut stream,
+            "MAIL FROM:<user@localhost>\r\nRCPT TO:<root@localhost>\r\nDATA\r\n",
+        )
+        .await;
+        assert!(reply.starts_with("250 Sender OK\r\n250 OK\r\n354"));
+        send_command(&mut stream, "Hello world\r\n.\r\n").await;
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert_eq!(mail.from, "user@localhost");
+
+        listening_server.stop().await.unwrap();
+    }
+
+    /// Refuses the first connection, fails the second temporarily and delays the others
+    #[derive(Debug, Default)]
+    struct TestConnectionPolicy(AtomicUsize);
+
+    #[async_trait]
+    impl ConnectionPolicy for TestConnectionPolicy {
+        async fn check_connection(
+            &self,
+            remote_addr: SocketAddr,
+            local_addr: SocketAddr,
+        ) -> Result<Admit, Reject> {
+            assert!(remote_addr.ip().is_loopback());
+            assert_eq!(local_addr.port(), 2547);
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(Reject::Permanent("No SMTP service here".to_string())),
+                1 => Err(Reject::Temporary("Too many connections".to_string())),
+                _ => Ok(Admit::Delay(Duration::from_millis(200))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_policy() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let server = SmtpServer::new(
+            "localhost".to_string(),
+            2547,
+            "localhost".to_string(),
+            Some(Duration::from_secs(10)),
+            None,
+            None,
+            None,
+        )
+        .connection_policy(TestConnectionPolicy::default());
+        let listening_server = server.start().await.unwrap();
+
+        // Refused clients are told why instead of being greeted, then disconnected
+        for expected in [
+            "554 No SMTP service here\r\n",
+            "421 Too many connections\r\n",
+        ] {
+            let mut stream = TcpStream::connect("127.0.0.1:2547").await.unwrap();
+            assert_eq!(read_reply(&mut stream).await, expected);
+            assert_eq!(read_reply(&mut stream).await, "");
+        }
+
+        // Admitted clients can be greeted after a delay
+        let started = std::time::Instant::now();
+        let mut stream = TcpStream::connect("127.0.0.1:2547").await.unwrap();
+        assert!(read_reply(&mut stream).await.starts_with("220"));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(send_command(&mut stream, "EHLO client\r\n")
+            .await
+            .starts_with("250"));
+
+        listening_server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_networks() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let new_server = |port| {
+            SmtpServer::new(
+                "127.0.0.1".to_string(),
+                port,
+                "localhost".to_string(),
+                Some(Duration::from_secs(10)),
+                None,
+                None,
+                None,
+            )
+        };
+        let networks = |networks: &[&str]| {
+            networks
+                .iter()
+                .map(|network| network.parse::<IpNetwork>().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Clients that are not on the allow list, or are on the deny list, are refused
+        let servers = [
+            (
+                2548,
+                new_server(2548).allowed_networks(networks(&["10.0.0.0/8", "::1/128"])),
+            ),
+            (
+                2549,
+                new_server(2549)
+                    .allowed_networks(networks(&["127.0.0.0/8"]))
+                    .denied_networks(networks(&["127.0.0.1/32"])),
+            ),
+        ];
+        for (port, server) in servers {
+            let listening_server = server.start().await.unwrap();
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let reply = read_reply(&mut stream).await;
+            assert_eq!(reply, "554 No SMTP service here\r\n");
+            assert_eq!(read_reply(&mut stream).await, "");
+            listening_server.stop().await.unwrap();
+        }
+
+        // Trusted relays are served and bypass the restrictions on senders and recipients
+        let server = new_server(2550)
+            .denied_networks(networks(&["127.0.0.0/8"]))
+            .trusted_networks(networks(&["127.0.0.1/32"]))
+            .authenticator(TestAuthenticator)
+            .require_auth(true)
+            .sender_policy(TestSenderPolicy)
+            .recipient_policy(TestRecipientPolicy);
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("127.0.0.1:2550").await.unwrap();
+        assert!(read_reply(&mut stream).await.starts_with("220"));
+        send_command(&mut stream, "EHLO relay\r\n").await;
+        let replies = [
+            ("MAIL FROM:<spammer@bad.example>\r\n", "250"),
+            ("RCPT TO:<unknown@localhost>\r\n", "250"),
+            ("DATA\r\n", "354"),
+            ("Hello world\r\n.\r\n", "250"),
+        ];
+        for (command, code) in replies {
+            let reply = send_command(&mut stream, command).await;
+            assert!(reply.starts_with(code), "{:?} => {:?}", command, reply);
+        }
+        let mail = listening_server.mail_rx.recv().await.unwrap();
+        assert!(mail.session.trusted);
+        assert_eq!(mail.authenticated, None);
+
+        listening_server.stop().await.unwrap();
+    }
+
+    /// Answers DNS queries for the given names with an A record, and any other with NXDOMAIN
+    async fn dns_stand_in(port: u16, answers: HashMap<&'static str, Ipv4Addr>) -> Arc<AtomicUsize> {
+        let socket = UdpSocket::bind(("127.0.0.1", port)).await.unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            loop {
+                let (n, client) = socket.recv_from(&mut buf).await.unwrap();
+                let request = DnsMessage::from_vec(&buf[..n]).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let mut response = DnsMessage::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(OpCode::Query)
+                    .set_recursion_desired(request.recursion_desired())
+                    .add_queries(request.queries().to_vec());
+                let name = request.queries()[0].name().clone();
+                match answers.get(name.to_string().as_str()) {
+                    Some(address) => {
+                        response.add_answer(Record::from_rdata(name, 60, RData::A(A(*address))));
+                    }
+                    None if name.to_string().ends_with(".fail.test.") => {
+                        response.set_response_code(ResponseCode::ServFail);
+                    }
+                    None => {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), client)
+                    .await
+                    .unwrap();
+            }
+        });
+        queries
+    }
+
+    #[tokio::test]
+    async fn test_dnsbl() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        // Addresses are queried in reverse order, by octet or by nibble
+        let zone = DnsblZone::new("zen.example", DnsblAction::Tag);
+        assert_eq!(
+            zone.query_name("192.0.2.99".parse().unwrap()),
+            "99.2.0.192.zen.example."
+        );
+        assert_eq!(
+            zone.query_name("2001:db8:1:2:3:4:567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.8.b.d.0.1.0.0.2.zen.example."
+        );
+
+        let listed = Ipv4Addr::new(127, 0, 0, 2);
+        let queries = dns_stand_in(
+            2553,
+            HashMap::from([
+                ("1.0.0.127.tag.test.", listed),
+                ("1.0.0.127.codes.test.", Ipv4Addr::new(127, 0, 0, 3)),
+                ("1.0.0.127.reject.test.", listed),
+            ]),
+        )
+        .await;
+        let dnsbl = |zones| {
+            let name_servers =
+                NameServerConfigGroup::from_ips_clear(&["127.0.0.1".parse().unwrap()], 2553, true);
+            let mut options = ResolverOpts::default();
+            // Only the cache of the blocklists is tested
+            options.cache_size = 0;
+            Dnsbl::with_resolver(
+                zones,
+                ResolverConfig::from_parts(None, vec![], name_servers),
+                options,
+            )
+        };
+        let new_server = |port, dnsbl| {
+            SmtpServer::new(
+                "127.0.0.1".to_string(),
+                port,
+                "localhost".to_string(),
+                Some(Duration::from_secs(10)),
+                None,
+                None,
+                None,
+            )
+            .dnsbl(dnsbl)
+        };
+
+        // Listings on tagging blocklists and unknown return codes do not refuse the client
+        let server = new_server(
+            2551,
+            dnsbl(vec![
+                DnsblZone::new("tag.test", DnsblAction::Tag),
+                DnsblZone::new("codes.test", DnsblAction::Reject).code(listed, "Spam source"),
+            ]),
+        );
+        let listening_server = server.start().await.unwrap();
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect("127.0.0.1:2551").await.unwrap();
+            assert!(read_reply(&mut stream).await.starts_with("220"));
+            send_command(&mut stream, "EHLO client\r\n").await;
+            send_command(&mut stream, "MAIL FROM:<user@localhost>\r\n").await;
+            send_command(&mut stream, "RCPT TO:<root@localhost>\r\n").await;
+            send_command(&mut stream, "DATA\r\n").await;
+            send_command(&mut stream, "Hello world\r\n.\r\n").await;
+
+            let mail = listening_server.mail_rx.recv().await.unwrap();
+            let expected = DnsblListing {
+                zone: "tag.test".to_string(),
+                action: DnsblAction::Tag,
+                code: listed,
+                meaning: None,
+            };
+            assert_eq!(mail.session.dnsbl, vec![expected]);
+        }
+        // The second client was not looked up again
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        listening_server.stop().await.unwrap();
+
+        // Listings on rejecting blocklists refuse the client with the meaning of the code
+        let server = new_server(
+            2552,
+            dnsbl(vec![
+                DnsblZone::new("reject.test", DnsblAction::Reject).code(listed, "Spam source")
+            ]),
+        );
+        let listening_server = server.start().await.unwrap();
+        let mut stream = TcpStream::connect("127.0.0.1:2552").await.unwrap();
+        assert_eq!(
+            read_reply(&mut stream).await,
+            "554 5.7.1 Service unavailable; client [127.0.0.1] blocked using reject.test; Spam source\r\n"
+        );
+        listening_server.stop().await.unwrap();
+
+        // Failed lookups are not cached, so the address is looked up again
+        let failing = dnsbl(vec![DnsblZone::new("fail.test", DnsblAction::Reject)]);
+        let ip = "127.0.0.1".parse().unwrap();
+        assert!(failing.check(ip).await.is_empty());
+        let before = queries.load(Ordering::SeqCst);
+        assert!(failing.check(ip).await.is_empty());
+        assert!(queries.load(Ordering::SeqCst) > before);
+    }
 }
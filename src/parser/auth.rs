@@ -0,0 +1,71 @@
+use std::slice::Split;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::io;
+
+use crate::{
+    auth::{Mechanism, Sasl},
+    connection::{Connection, State, Stream},
+    parser::{
+        responses::{
+            ALREADY_AUTHENTICATED, COMMAND_NOT_IMPLEMENTED, ENCRYPTION_REQUIRED, INVALID_ARGUMENTS,
+            MECHANISM_NOT_SUPPORTED,
+        },
+        Response,
+    },
+};
+
+pub fn auth(
+    connection: &mut Connection,
+    mut command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+    domain: String,
+) -> Result<Response, io::Error> {
+    log::info!("Command received: AUTH");
+    if connection.authenticator.is_none()
+        && connection.credential_store.is_none()
+        && connection.token_verifier.is_none()
+    {
+        return Ok(COMMAND_NOT_IMPLEMENTED.into());
+    }
+    // A client can only authenticate once per session (RFC 4954)
+    if connection.authenticated.is_some() {
+        return Ok(ALREADY_AUTHENTICATED.into());
+    }
+
+    let Some(Ok(name)) = command.next().map(std::str::from_utf8) else {
+        return Ok(INVALID_ARGUMENTS.into());
+    };
+    let Some(mechanism) = Mechanism::ALL
+        .into_iter()
+        .find(|mechanism| mechanism.name().eq_ignore_ascii_case(name))
+    else {
+        log::error!("Unsupported authentication mechanism: {:?}", name);
+        return Ok(MECHANISM_NOT_SUPPORTED.into());
+    };
+    if !connection.mechanisms().contains(&mechanism) {
+        let encrypted = matches!(connection.stream, Stream::Encrypted(_));
+        return Ok(if mechanism.requires_tls() && !encrypted {
+            log::error!("{} requires an encrypted connection", mechanism.name());
+            ENCRYPTION_REQUIRED
+        } else {
+            log::error!("{} is not enabled", mechanism.name());
+            MECHANISM_NOT_SUPPORTED
+        }
+        .into());
+    }
+
+    let sasl = Sasl::new(mechanism);
+    let challenge = sasl.initial_challenge();
+    connection.state = State::Auth(domain, sasl);
+
+    // The initial response sent with the command is processed like any other response
+    match command.find(|argument| !argument.is_empty()) {
+        Some(response) => {
+            connection.sasl_response = Some(response.to_vec());
+            Ok(Response::default())
+        }
+        None => Ok(format!("334 {}\r\n", STANDARD.encode(challenge))
+            .into_bytes()
+            .into()),
+    }
+}
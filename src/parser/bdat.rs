@@ -0,0 +1,53 @@
+use std::slice::Split;
+
+use tokio::io;
+
+use crate::{
+    connection::{Connection, State},
+    parser::{
+        responses::{INVALID_ARGUMENTS, NEED_EHLO, NEED_MAIL, NEED_RCPT},
+        Response,
+    },
+};
+
+pub fn bdat(
+    connection: &mut Connection,
+    command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+) -> Result<Response, io::Error> {
+    log::info!("Command received: BDAT");
+    // The size of the chunk, optionally followed by LAST for the final chunk
+    let mut arguments = command.filter(|argument| !argument.is_empty());
+    let size = arguments
+        .next()
+        .and_then(|size| std::str::from_utf8(size).ok()?.parse::<usize>().ok());
+    let last = match arguments.next() {
+        None => Some(false),
+        Some(argument) if argument.eq_ignore_ascii_case(b"LAST") => Some(true),
+        Some(_) => None,
+    };
+    let (Some(size), Some(last), None) = (size, last, arguments.next()) else {
+        log::error!("Invalid BDAT syntax");
+        return Ok(INVALID_ARGUMENTS.into());
+    };
+
+    // Chunks can be sent once there is at least one recipient, or after a previous chunk
+    let mail = match std::mem::replace(&mut connection.state, State::Initial) {
+        State::MailFrom(mail) if !mail.to.is_empty() => mail,
+        State::Chunking(mail) => mail,
+        state => {
+            // The client sends the chunk right after the command, so it has to be skipped
+            connection.discard = size;
+            let response = match state {
+                State::Initial => NEED_EHLO,
+                State::MailFrom(_) => NEED_RCPT,
+                _ => NEED_MAIL,
+            };
+            connection.state = state;
+            return Ok(response.into());
+        }
+    };
+
+    log::info!("Awaiting chunk of {} bytes...", size);
+    connection.state = State::Chunk(mail, size, last);
+    Ok(Response::default())
+}
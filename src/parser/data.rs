@@ -1,34 +1,73 @@
+use std::slice::Split;
+
 use tokio::io;
 
 use crate::{
-    connection::{Connection, Mail, State},
-    parser::responses::{OK, SEND_DATA},
+    connection::{BodyType, Connection, Mail, State},
+    parser::{
+        no_arguments,
+        responses::{
+            BAD_SEQUENCE, BINARYMIME_REQUIRES_BDAT, INVALID_ARGUMENTS, MESSAGE_TOO_LARGE,
+            NEED_RCPT, SEND_DATA,
+        },
+        Response,
+    },
 };
 
 pub fn prepare_for_data(
     connection: &mut Connection,
+    command: Split<'_, u8, impl FnMut(&u8) -> bool>,
     mail: Mail,
-) -> Result<&'static [u8], io::Error> {
+) -> Result<Response, io::Error> {
     log::info!("Command received: DATA");
+    if !no_arguments(command) {
+        return Ok(INVALID_ARGUMENTS.into());
+    }
+    // A mail cannot be delivered without at least one recipient
+    if mail.to.is_empty() {
+        log::error!("No recipients given");
+        return Ok(NEED_RCPT.into());
+    }
+    // Binary data cannot be sent with DATA, because lines are not preserved
+    if mail.body == BodyType::BinaryMime {
+        log::error!("BINARYMIME requires BDAT");
+        return Ok(BINARYMIME_REQUIRES_BDAT.into());
+    }
     log::info!("Awaiting data...");
     connection.state = State::Data(mail);
 
-    Ok(SEND_DATA)
+    Ok(SEND_DATA.into())
 }
 
-pub fn data(
-    connection: &mut Connection,
-    mut mail: Mail,
-    raw_command: &[u8],
-) -> Result<&'static [u8], io::Error> {
-    log::info!("Some data received");
-    // Append the data to the mail
-    mail.data.extend_from_slice(&raw_command);
-    connection.state = State::Data(mail);
-    if raw_command.ends_with("\r\n.\r\n".as_bytes()) {
-        log::info!("Data received successfully");
-        Ok(OK)
-    } else {
-        Ok(&[])
+pub fn data(connection: &mut Connection, raw_command: &[u8]) -> Result<Response, io::Error> {
+    // A line with a single dot ends the mail data, the terminator is not part of the mail
+    let end_of_data = raw_command == b".\r\n";
+
+    match &mut connection.state {
+        State::Data(mail) if end_of_data => {
+            log::info!("Data received successfully");
+            // The mail is complete, the reply is sent once it has been delivered
+            connection.state = State::Received(std::mem::take(mail));
+            Ok(Response::default())
+        }
+        State::Data(mail) => {
+            // Lines starting with a dot have been dot-stuffed by the client (RFC 5321 section 4.5.2)
+            let line = raw_command.strip_prefix(b".").unwrap_or(raw_command);
+
+            // Once the message is too large, the rest of the data is read but not stored
+            if mail.data.len() + line.len() > connection.max_message_size {
+                log::error!("Message exceeds {} bytes", connection.max_message_size);
+                connection.state = State::Oversized(std::mem::take(&mut mail.domain));
+            } else {
+                mail.data.extend_from_slice(line);
+            }
+            Ok(Response::default())
+        }
+        State::Oversized(domain) if end_of_data => {
+            connection.state = State::Ehlo(std::mem::take(domain));
+            Ok(MESSAGE_TOO_LARGE.into())
+        }
+        State::Oversized(_) => Ok(Response::default()),
+        _ => Ok(BAD_SEQUENCE.into()),
     }
 }
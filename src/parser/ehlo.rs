@@ -2,27 +2,53 @@ use std::slice::Split;
 use tokio::io;
 
 use crate::{
-    connection::{Connection, State, TlsConfig},
-    parser::responses::{EHLO_TLS_AVAILABLE, EHLO_TLS_UNAVAILABLE},
+    connection::{Connection, State, Stream, TlsConfig},
+    parser::{
+        responses::{multiline, DOMAIN, INVALID_ARGUMENTS},
+        Response,
+    },
 };
 
 pub fn ehlo(
     connection: &mut Connection,
-    mut command: Split<'_, u8, impl FnMut(&u8) -> bool>
-) -> Result<&'static [u8], io::Error> {
+    mut command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+) -> Result<Response, io::Error> {
     log::info!("Command received: EHLO");
-    log::info!("Sending 250 response");
-    // Read the domain from the command
-    if let Some(domain) = command.next() {
-        let domain_str = std::str::from_utf8(domain).unwrap();
-        log::info!("Domain: {}", domain_str);
-        connection.state = State::Ehlo(domain_str.to_string());
-    } else {
-        connection.state = State::Ehlo("".to_string());
+    // Read the domain from the command, RFC 5321 requires it
+    match command.next().map(std::str::from_utf8) {
+        Some(Ok(domain_str)) if !domain_str.is_empty() => {
+            log::info!("Domain: {}", domain_str);
+            connection.state = State::Ehlo(domain_str.to_string());
+            connection.session.helo = Some(domain_str.to_string());
+            connection.session.esmtp = true;
+        }
+        _ => {
+            log::error!("Invalid domain");
+            return Ok(INVALID_ARGUMENTS.into());
+        }
     }
-    // Return based on the TLS configuration
-    Ok(match connection.tls_config {
-        TlsConfig::Encrypted { .. } => &EHLO_TLS_AVAILABLE,
-        _ => &EHLO_TLS_UNAVAILABLE,
-    })
+
+    // The first line is our domain, followed by the supported service extensions
+    let mut lines = vec![
+        DOMAIN.to_string(),
+        "PIPELINING".to_string(),
+        format!("SIZE {}", connection.max_message_size),
+        "8BITMIME".to_string(),
+        "SMTPUTF8".to_string(),
+        "CHUNKING".to_string(),
+        "BINARYMIME".to_string(),
+    ];
+    // Authentication is only advertised with the mechanisms usable on this connection
+    let mechanisms = connection.mechanisms();
+    if !mechanisms.is_empty() {
+        let names = mechanisms.iter().map(|m| m.name()).collect::<Vec<_>>();
+        lines.push(format!("AUTH {}", names.join(" ")));
+    }
+    // Based on the TLS configuration, STARTTLS is not offered again once encrypted
+    if let (Stream::Plain(_), TlsConfig::Encrypted(_)) =
+        (&connection.stream, &connection.tls_config)
+    {
+        lines.push("STARTTLS".to_string());
+    }
+    Ok(multiline(250, &lines).into())
 }
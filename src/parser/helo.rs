@@ -3,23 +3,30 @@ use tokio::io;
 
 use crate::{
     connection::{Connection, State},
-    parser::responses::EHLO_TLS_UNAVAILABLE,
+    parser::{
+        responses::{HELO, INVALID_ARGUMENTS},
+        Response,
+    },
 };
 
 pub fn helo(
     connection: &mut Connection,
-    mut command: Split<'_, u8, impl FnMut(&u8) -> bool>
-) -> Result<&'static [u8], io::Error> {
+    mut command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+) -> Result<Response, io::Error> {
     log::info!("Command received: HELO");
-    log::info!("Sending 250 response");
-    // Read the domain from the command
-    if let Some(domain) = command.next() {
-        let domain_str = std::str::from_utf8(domain).unwrap();
-        log::info!("Domain: {}", domain_str);
-        connection.state = State::Ehlo(domain_str.to_string());
-    } else {
-        connection.state = State::Ehlo("".to_string());
+    // Read the domain from the command, RFC 5321 requires it
+    match command.next().map(std::str::from_utf8) {
+        Some(Ok(domain_str)) if !domain_str.is_empty() => {
+            log::info!("Domain: {}", domain_str);
+            connection.state = State::Ehlo(domain_str.to_string());
+            connection.session.helo = Some(domain_str.to_string());
+            connection.session.esmtp = false;
+        }
+        _ => {
+            log::error!("Invalid domain");
+            return Ok(INVALID_ARGUMENTS.into());
+        }
     }
-    // We never support TLS on HELO
-    Ok(&EHLO_TLS_UNAVAILABLE)
+    // HELO does not support any service extension, including TLS
+    Ok(HELO.as_slice().into())
 }
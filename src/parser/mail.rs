@@ -3,38 +3,91 @@ use std::slice::Split;
 use tokio::io;
 
 use crate::{
-    connection::{Connection, Mail, State},
-    parser::{extract_email, responses::OK},
+    connection::{unique_id, BodyType, Connection, Mail, State},
+    parser::{
+        extract_path, is_valid_address,
+        responses::{
+            AUTH_REQUIRED, INVALID_ARGUMENTS, MAILBOX_NOT_ALLOWED, MESSAGE_TOO_LARGE, OK,
+            PARAMETERS_NOT_RECOGNIZED,
+        },
+        Response,
+    },
 };
 
 pub fn mail(
     connection: &mut Connection,
-    mut command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+    command: Split<'_, u8, impl FnMut(&u8) -> bool>,
     domain: String,
-) -> Result<&'static [u8], io::Error> {
+) -> Result<Response, io::Error> {
     log::info!("Command received: MAIL");
-    match command.next() {
-        Some(email) => {
-            // Extract the email from the command
-            let email_str = std::str::from_utf8(email).unwrap();
-            let extracted_email = extract_email(email_str);
+    // Trusted relays may send mail without authenticating
+    if connection.require_auth && connection.authenticated.is_none() && !connection.session.trusted
+    {
+        log::error!("MAIL FROM before authentication");
+        return Ok(AUTH_REQUIRED.into());
+    }
+    // Extract the reverse-path from the command
+    let Some(path) = extract_path(command, "FROM:") else {
+        log::error!("Invalid MAIL FROM syntax");
+        return Ok(INVALID_ARGUMENTS.into());
+    };
 
-            if let Some(email) = extracted_email {
-                connection.state = State::MailFrom(Mail {
-                    from: email.to_owned(),
-                    domain,
-                    ..Default::default()
-                });
-                log::info!("Sender: {:?}", email);
-            } else {
-                connection.state = State::Invalid;
-                log::error!("Invalid Sender");
+    let mut body = BodyType::SevenBit;
+    let mut smtputf8 = false;
+    for parameter in &path.parameters {
+        let (keyword, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        match keyword.to_uppercase().as_str() {
+            // The client declares the size of the message in advance (RFC 1870)
+            "SIZE" => match value.parse::<usize>() {
+                Ok(size) if size > connection.max_message_size => {
+                    log::error!("Declared message size too large: {}", size);
+                    return Ok(MESSAGE_TOO_LARGE.into());
+                }
+                Ok(_) => {}
+                Err(_) => return Ok(INVALID_ARGUMENTS.into()),
+            },
+            // The client declares whether the message contains 8-bit or binary data (RFC 6152, 3030)
+            "BODY" => match value.to_uppercase().as_str() {
+                "7BIT" => body = BodyType::SevenBit,
+                "8BITMIME" => body = BodyType::EightBitMime,
+                "BINARYMIME" => body = BodyType::BinaryMime,
+                _ => return Ok(INVALID_ARGUMENTS.into()),
+            },
+            // The client uses UTF-8 in addresses and headers (RFC 6531)
+            "SMTPUTF8" if value.is_empty() => smtputf8 = true,
+            // The identity that submitted the message is informational only (RFC 4954)
+            "AUTH" => {}
+            _ => {
+                log::error!("Unsupported MAIL FROM parameter: {:?}", parameter);
+                return Ok(PARAMETERS_NOT_RECOGNIZED.into());
             }
         }
-        None => {
-            connection.state = State::Invalid;
-            log::error!("Invalid Sender");
-        }
     }
-    Ok(OK)
+
+    // The null reverse-path is used for notifications such as bounces
+    if !path.address.is_empty() && !is_valid_address(&path.address, smtputf8) {
+        log::error!("Invalid Sender: {:?}", path.address);
+        return Ok(MAILBOX_NOT_ALLOWED.into());
+    }
+
+    let queue_id = unique_id();
+    log::info!("Sender: {:?}, queue id: {}", path.address, queue_id);
+    let mail = Mail {
+        from: path.address,
+        domain,
+        body,
+        smtputf8,
+        authenticated: connection.authenticated.clone(),
+        tls: connection.tls.clone(),
+        session: connection.session.clone(),
+        queue_id,
+        ..Default::default()
+    };
+    // The sender policy is consulted before the transaction starts, except for trusted relays
+    if connection.sender_policy.is_some() && !connection.session.trusted {
+        connection.pending_sender = Some((mail, path.parameters));
+        return Ok(Response::default());
+    }
+    connection.state = State::MailFrom(mail);
+    Ok(OK.into())
 }
@@ -1,97 +1,171 @@
+mod auth;
+mod bdat;
 mod data;
 mod ehlo;
 mod helo;
 mod mail;
 mod rcpt;
 pub mod responses;
+mod rset;
 mod starttls;
 
+use auth::auth;
+use bdat::bdat;
 use data::{data, prepare_for_data};
 use ehlo::ehlo;
 use helo::helo;
 use mail::mail;
 use rcpt::rcpt;
-use responses::QUIT;
+use responses::{
+    BAD_SEQUENCE, COMMAND_NOT_IMPLEMENTED, COMMAND_UNRECOGNIZED, NEED_EHLO, NEED_MAIL, OK, QUIT,
+    STARTTLS_REQUIRED,
+};
+use rset::rset;
 use starttls::starttls;
+use std::borrow::Cow;
 use tokio::io;
 
-use crate::connection::{Connection, State};
-
-fn extract_email(email: &str) -> Option<&str> {
-    let chars = email.chars().enumerate();
-    let mut at = false; // Tracks presence of '@'
-    let mut start = None;
-    let mut end = None;
-
-    for (i, c) in chars {
-        match c {
-            '<' => start = Some(i + 1), // Start after '<'
-            '>' => {
-                end = Some(i); // End before '>'
-                break; // No need to look further beyond '>'
-            }
-            '@' => {
-                if start.is_some() && end.is_none() {
-                    at = true;
-                }
-            }
-            _ => {}
-        }
+use crate::connection::{Connection, State, Stream};
+
+/**
+   The reply to a command, most replies are static but some are built for the connection.
+*/
+pub type Response = Cow<'static, [u8]>;
+
+/**
+## Path struct
+   The `Path` struct represents the argument of a MAIL FROM or RCPT TO command.
+   It includes the following fields:
+   - `address`: The address between the angle brackets, empty for the null reverse-path.
+   - `parameters`: The ESMTP parameters following the path.
+*/
+struct Path {
+    address: String,
+    parameters: Vec<String>,
+}
+
+/**
+   Extracts the path from the arguments of a MAIL FROM or RCPT TO command,
+   e.g. `FROM:<user@domain> SIZE=100`. Returns `None` if the arguments are malformed.
+*/
+fn extract_path<'a>(arguments: impl Iterator<Item = &'a [u8]>, keyword: &str) -> Option<Path> {
+    let arguments = arguments
+        .filter(|argument| !argument.is_empty())
+        .map(std::str::from_utf8)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?
+        .join(" ");
+
+    // The keyword is case insensitive and some clients put a space before the path
+    if !arguments
+        .get(..keyword.len())?
+        .eq_ignore_ascii_case(keyword)
+    {
+        return None;
     }
+    let path = arguments[keyword.len()..].trim_start().strip_prefix('<')?;
+    let end = path.find('>')?;
 
-    if let (Some(st), Some(en)) = (start, end) {
-        if at && st < en {
-            // Additional checks to ensure indices and presence of '@'
-            return Some(&email[st..en]);
-        }
+    // Source routes (`<@relay1,@relay2:user@domain>`) must be accepted but are ignored
+    let address = match path[..end].split_once(':') {
+        Some((route, address)) if route.starts_with('@') => address,
+        _ => &path[..end],
+    };
+
+    Some(Path {
+        address: address.to_string(),
+        parameters: path[end + 1..]
+            .split_whitespace()
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+/**
+   Checks that an address has a local part and a domain, and only contains printable ASCII characters.
+   If `utf8` is set, UTF-8 local parts and internationalized domains are allowed as well (RFC 6531).
+*/
+fn is_valid_address(address: &str, utf8: bool) -> bool {
+    let printable = address.chars().all(|c| {
+        c.is_ascii_graphic() || (utf8 && !c.is_ascii() && !c.is_control() && !c.is_whitespace())
+    });
+    match address.rsplit_once('@') {
+        Some((local, domain)) => printable && !local.is_empty() && !domain.is_empty(),
+        None => false,
     }
-    None
+}
+
+/**
+   Checks that a command was sent without any arguments.
+*/
+fn no_arguments<'a>(mut arguments: impl Iterator<Item = &'a [u8]>) -> bool {
+    arguments.all(|argument| argument.is_empty())
 }
 
 pub fn parse_and_execute(
     connection: &mut Connection,
     raw_command: &[u8],
-) -> Result<&'static [u8], io::Error> {
+) -> Result<Response, io::Error> {
+    // While receiving mail data, every line belongs to the mail, even if it looks like a command
+    if let State::Data(_) | State::Oversized(_) = connection.state {
+        return data(connection, raw_command);
+    }
+
     log::info!("SMTP Processor: Processing command...");
 
     // Split the received data by whitespace
     let mut commands = raw_command.split(|c| *c == b' ' || *c == b'\r' || *c == b'\n');
-    // let mut command: std::str::SplitWhitespace<'_> = raw_command.split_whitespace();
 
     // The first phrase in the command is the command itself
-    // We match the command to a handler based on the current state of the connection
-    let command = match commands.next() {
-        Some(c) => c,
-        None => {
+    let command = match commands.next().map(std::str::from_utf8) {
+        Some(Ok(command)) => command.to_lowercase(),
+        _ => {
             log::error!("Invalid command {:?}", raw_command);
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid command",
-            ));
+            return Ok(COMMAND_UNRECOGNIZED.into());
         }
     };
-    
-    let command_string = std::str::from_utf8(command).unwrap().to_lowercase();
-    let command_str = command_string.as_str();
-    log::info!("Received command: {:?}", command_str);
-    match (command_str, connection.state.clone()) {
-        ("ehlo", State::Initial) => ehlo(connection, commands),
-        ("helo", State::Initial) => helo(connection, commands),
-        ("starttls", State::Ehlo(_domain)) => starttls(connection),
+    log::info!("Received command: {:?}", command);
+
+    // With the TLS policy enforced, nothing is accepted on a plaintext connection (RFC 3207)
+    if connection.require_tls
+        && matches!(connection.stream, Stream::Plain(_))
+        && matches!(command.as_str(), "mail" | "rcpt" | "auth")
+    {
+        log::error!("{} refused before STARTTLS", command.to_uppercase());
+        return Ok(STARTTLS_REQUIRED.into());
+    }
+
+    // We match the command to a handler based on the current state of the connection
+    match (command.as_str(), connection.state.clone()) {
+        ("ehlo", _) => ehlo(connection, commands),
+        ("helo", _) => helo(connection, commands),
+        ("starttls", State::Ehlo(_domain)) => starttls(connection, commands),
+        ("auth", State::Ehlo(domain)) => auth(connection, commands, domain),
         ("mail", State::Ehlo(domain)) => mail(connection, commands, domain),
         ("rcpt", State::MailFrom(mail)) => rcpt(connection, commands, mail),
-        ("data", State::MailFrom(mail)) => prepare_for_data(connection, mail),
+        ("data", State::MailFrom(mail)) => prepare_for_data(connection, commands, mail),
+        ("bdat", _) => bdat(connection, commands),
+        ("rset", _) => rset(connection, commands),
+        ("noop", _) => {
+            log::info!("Command received: NOOP");
+            Ok(OK.into())
+        }
         ("quit", _) => {
             log::info!("Command received: QUIT");
-            return Ok(QUIT);
+            Ok(QUIT.into())
+        }
+        // The command is known, but not allowed in the current state
+        ("rcpt" | "data", State::Chunking(_)) => Ok(BAD_SEQUENCE.into()),
+        ("mail" | "rcpt" | "data" | "starttls" | "auth", State::Initial) => Ok(NEED_EHLO.into()),
+        ("rcpt" | "data", _) => Ok(NEED_MAIL.into()),
+        ("mail" | "starttls" | "auth", _) => Ok(BAD_SEQUENCE.into()),
+        ("vrfy" | "expn" | "help" | "turn" | "send" | "soml" | "saml", _) => {
+            log::error!("Command not implemented {:?}", command);
+            Ok(COMMAND_NOT_IMPLEMENTED.into())
         }
-        (_, State::Data(mail)) => data(connection, mail, raw_command),
         _ => {
             log::error!("Invalid command {:?}", command);
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid command",
-            ));
+            Ok(COMMAND_UNRECOGNIZED.into())
         }
     }
 }
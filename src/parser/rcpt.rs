@@ -4,39 +4,53 @@ use tokio::io;
 
 use crate::{
     connection::{Connection, Mail, State},
-    parser::{extract_email, responses::OK},
+    parser::{
+        extract_path, is_valid_address,
+        responses::{INVALID_ARGUMENTS, MAILBOX_NOT_ALLOWED, OK, PARAMETERS_NOT_RECOGNIZED},
+        Response,
+    },
 };
 
 pub fn rcpt(
     connection: &mut Connection,
-    mut command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+    command: Split<'_, u8, impl FnMut(&u8) -> bool>,
     mail: Mail,
-) -> Result<&'static [u8], io::Error> {
+) -> Result<Response, io::Error> {
     log::info!("Command received: RCPT");
-    match command.next() {
-        Some(email) => {
-            // Extract the email from the command
-            let email_str = std::str::from_utf8(email).unwrap();
-            let extracted_email = extract_email(email_str);
-            if let Some(email) = extracted_email {
-                // Add the recipient to the list of recipients
-                let mut current_recipients = mail.to.clone();
-                current_recipients.push(email.to_owned());
-                log::info!("Recipients: {:?}", current_recipients);
-                // Update the connection state
-                connection.state = State::MailFrom(Mail {
-                    to: current_recipients,
-                    ..mail.clone()
-                });
-            } else {
-                connection.state = State::Invalid;
-                log::error!("Invalid recipient");
-            }
-        }
-        None => {
-            connection.state = State::Invalid;
-            log::error!("Invalid recipient");
-        }
+    // Extract the forward-path from the command
+    let Some(path) = extract_path(command, "TO:") else {
+        log::error!("Invalid RCPT TO syntax");
+        return Ok(INVALID_ARGUMENTS.into());
+    };
+
+    // The postmaster mailbox may be addressed without a domain, and UTF-8 addresses
+    // are only allowed if the client requested SMTPUTF8 for this transaction
+    if !path.address.eq_ignore_ascii_case("postmaster")
+        && !is_valid_address(&path.address, mail.smtputf8)
+    {
+        log::error!("Invalid recipient: {:?}", path.address);
+        return Ok(MAILBOX_NOT_ALLOWED.into());
+    }
+
+    if !path.parameters.is_empty() {
+        log::error!("Unsupported RCPT TO parameters: {:?}", path.parameters);
+        return Ok(PARAMETERS_NOT_RECOGNIZED.into());
     }
-    Ok(OK)
+
+    // The recipient policy is consulted before the recipient is added, except for trusted relays
+    if connection.recipient_policy.is_some() && !connection.session.trusted {
+        connection.pending_recipient = Some(path.address);
+        return Ok(Response::default());
+    }
+
+    // Add the recipient to the list of recipients
+    let mut current_recipients = mail.to.clone();
+    current_recipients.push(path.address);
+    log::info!("Recipients: {:?}", current_recipients);
+    // Update the connection state
+    connection.state = State::MailFrom(Mail {
+        to: current_recipients,
+        ..mail
+    });
+    Ok(OK.into())
 }
@@ -2,16 +2,52 @@ use std::{env, sync::LazyLock};
 
 // Responses as per RFC 5321
 // The following are the responses that the server can send to the client as per RFC 5321:
-pub static EHLO_TLS_AVAILABLE:LazyLock<Vec<u8>> = LazyLock::new(|| {
-    let domain=env::var("MINISMTP_DOMAIN").unwrap_or_else(|_| "minismtp".to_string());
-    format!("250-{}\r\n250 STARTTLS\r\n",domain).as_bytes().to_vec()
+pub static DOMAIN:LazyLock<String> = LazyLock::new(|| {
+    env::var("MINISMTP_DOMAIN").unwrap_or_else(|_| "minismtp".to_string())
 });
-pub static EHLO_TLS_UNAVAILABLE:LazyLock<Vec<u8>> = LazyLock::new(|| {
-    let domain=env::var("MINISMTP_DOMAIN").unwrap_or_else(|_| "minismtp".to_string());
-    format!("250 {}\r\n",domain).as_bytes().to_vec()
+pub static HELO:LazyLock<Vec<u8>> = LazyLock::new(|| {
+    format!("250 {}\r\n",*DOMAIN).as_bytes().to_vec()
 });
 pub static OK: &[u8] = b"250 OK\r\n";
 pub static READY_FOR_TLS: &[u8] = b"220 Ready to start TLS\r\n";
 pub static TLS_NOT_AVAILABLE: &[u8] = b"502 TLS not available\r\n";
 pub static SEND_DATA: &[u8] = b"354 Start mail input; end with <CRLF>.<CRLF>\r\n";
 pub static QUIT: &[u8] = b"221 Bye\r\n";
+pub static AUTH_SUCCESSFUL: &[u8] = b"235 Authentication successful\r\n";
+pub static LOCAL_ERROR: &[u8] = b"451 Requested action aborted: local error in processing\r\n";
+pub static COMMAND_UNRECOGNIZED: &[u8] = b"500 Syntax error, command unrecognized\r\n";
+pub static LINE_TOO_LONG: &[u8] = b"500 Line too long\r\n";
+pub static INVALID_ARGUMENTS: &[u8] = b"501 Syntax error in parameters or arguments\r\n";
+pub static AUTH_CANCELLED: &[u8] = b"501 Authentication cancelled\r\n";
+pub static COMMAND_NOT_IMPLEMENTED: &[u8] = b"502 Command not implemented\r\n";
+pub static BAD_SEQUENCE: &[u8] = b"503 Bad sequence of commands\r\n";
+pub static NEED_EHLO: &[u8] = b"503 Send HELO/EHLO first\r\n";
+pub static NEED_MAIL: &[u8] = b"503 Need MAIL command first\r\n";
+pub static NEED_RCPT: &[u8] = b"503 Need RCPT command first\r\n";
+pub static BINARYMIME_REQUIRES_BDAT: &[u8] = b"503 BINARYMIME requires BDAT\r\n";
+pub static TLS_ALREADY_ACTIVE: &[u8] = b"503 TLS already active\r\n";
+pub static ALREADY_AUTHENTICATED: &[u8] = b"503 Already authenticated\r\n";
+pub static MECHANISM_NOT_SUPPORTED: &[u8] = b"504 Unrecognized authentication type\r\n";
+pub static AUTH_REQUIRED: &[u8] = b"530 Authentication required\r\n";
+pub static STARTTLS_REQUIRED: &[u8] = b"530 Must issue a STARTTLS command first\r\n";
+pub static AUTH_FAILED: &[u8] = b"535 Authentication credentials invalid\r\n";
+pub static ENCRYPTION_REQUIRED: &[u8] =
+    b"538 Encryption required for requested authentication mechanism\r\n";
+pub static NO_SERVICE: &[u8] = b"554 No SMTP service here\r\n";
+pub static MAILBOX_NOT_ALLOWED: &[u8] =
+    b"553 Requested action not taken: mailbox name not allowed\r\n";
+pub static MESSAGE_TOO_LARGE: &[u8] = b"552 Message exceeds fixed maximum message size\r\n";
+pub static PARAMETERS_NOT_RECOGNIZED: &[u8] =
+    b"555 MAIL FROM/RCPT TO parameters not recognized or not implemented\r\n";
+
+/**
+   Builds a multiline reply, where every line but the last one has a hyphen after the code.
+*/
+pub fn multiline(code: u16, lines: &[String]) -> Vec<u8> {
+    let mut reply = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let separator = if i + 1 == lines.len() { ' ' } else { '-' };
+        reply.extend_from_slice(format!("{}{}{}\r\n", code, separator, line).as_bytes());
+    }
+    reply
+}
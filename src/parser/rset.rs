@@ -0,0 +1,29 @@
+use std::slice::Split;
+
+use tokio::io;
+
+use crate::{
+    connection::{Connection, State},
+    parser::{
+        no_arguments,
+        responses::{INVALID_ARGUMENTS, OK},
+        Response,
+    },
+};
+
+pub fn rset(
+    connection: &mut Connection,
+    command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+) -> Result<Response, io::Error> {
+    log::info!("Command received: RSET");
+    if !no_arguments(command) {
+        return Ok(INVALID_ARGUMENTS.into());
+    }
+    // Abort the current mail transaction, the EHLO/HELO greeting stays valid
+    connection.state = match connection.state.clone() {
+        State::Ehlo(domain) => State::Ehlo(domain),
+        State::MailFrom(mail) | State::Chunking(mail) => State::Ehlo(mail.domain),
+        _ => State::Initial,
+    };
+    Ok(OK.into())
+}
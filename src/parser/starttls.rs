@@ -1,18 +1,35 @@
+use std::slice::Split;
+
 use tokio::io;
 
 use crate::{
-    connection::{Connection, State, TlsConfig},
-    parser::responses::{READY_FOR_TLS, TLS_NOT_AVAILABLE},
+    connection::{Connection, State, Stream, TlsConfig},
+    parser::{
+        no_arguments,
+        responses::{INVALID_ARGUMENTS, READY_FOR_TLS, TLS_ALREADY_ACTIVE, TLS_NOT_AVAILABLE},
+        Response,
+    },
 };
 
-pub fn starttls(connection: &mut Connection) -> Result<&'static [u8], io::Error> {
+pub fn starttls(
+    connection: &mut Connection,
+    command: Split<'_, u8, impl FnMut(&u8) -> bool>,
+) -> Result<Response, io::Error> {
     log::info!("Command received: STARTTLS");
+    if !no_arguments(command) {
+        return Ok(INVALID_ARGUMENTS.into());
+    }
+    // A connection can only be upgraded once
+    if let Stream::Encrypted(_) = connection.stream {
+        return Ok(TLS_ALREADY_ACTIVE.into());
+    }
     // Check if the tls configuration allows for encryption
     Ok(match connection.tls_config {
-        TlsConfig::Encrypted { .. } => {
+        TlsConfig::Encrypted(_) => {
             connection.state = State::StartTls;
             READY_FOR_TLS
         }
         _ => TLS_NOT_AVAILABLE,
-    })
+    }
+    .into())
 }
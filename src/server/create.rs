@@ -1,11 +1,20 @@
-use std::{marker::PhantomData, path::PathBuf, time::Duration};
+use std::{collections::HashMap, marker::PhantomData, path::PathBuf, sync::Arc, time::Duration};
 
 use async_std::channel::unbounded;
+use ipnetwork::IpNetwork;
 use tokio::task;
+use tokio_rustls::rustls::ServerConfig;
 
-use crate::connection::Mail;
+use crate::{
+    auth::{Authenticator, CredentialStore, TokenVerifier},
+    connection::Mail,
+    dnsbl::Dnsbl,
+    hooks::{ConnectionPolicy, MessageHandler, RecipientPolicy, SenderPolicy},
+};
 
-use super::{start::start_server, Closed, Config, Listening, ServerError, SmtpServer};
+use super::{
+    start::start_server, tls, Closed, Config, Listening, ServerError, SharedTlsConfig, SmtpServer,
+};
 
 impl SmtpServer {
     /**
@@ -31,9 +40,9 @@ impl SmtpServer {
        #[tokio::main]
        async fn main() {
            let server = SmtpServer::new(
-               "localhost",
+               "localhost".to_string(),
                2525,
-               "localhost",
+               "localhost".to_string(),
                Some(Duration::from_secs(10)),
                Some(1024),
                None,
@@ -51,10 +60,6 @@ impl SmtpServer {
         certs_path: Option<PathBuf>,
         key_path: Option<PathBuf>,
     ) -> SmtpServer<Closed> {
-        if certs_path.is_none() || key_path.is_none() {
-            log::info!("No certificates or keys provided, STARTTLS will not be available.")
-        }
-
         let (mail_tx, mail_rx) = unbounded::<Mail>();
         let (affirm_tx, affirm_rx) = unbounded();
         let (shutdown_tx, shutdown_rx) = unbounded();
@@ -72,6 +77,31 @@ impl SmtpServer {
                 buffer_size,
                 certs_path,
                 key_path,
+                certs_pem: None,
+                client_ca_path: None,
+                require_client_certificate: false,
+                client_certificate_auth: false,
+                sni_certificates: HashMap::new(),
+                tls_config: None,
+                prebuilt_tls: false,
+                watch_interval: None,
+                max_message_size: None,
+                authenticator: None,
+                credential_store: None,
+                token_verifier: None,
+                require_auth: false,
+                require_tls: false,
+                implicit_tls: false,
+                trace_headers: false,
+                message_handler: None,
+                message_handler_timeout: None,
+                recipient_policy: None,
+                sender_policy: None,
+                connection_policy: None,
+                allowed_networks: Vec::new(),
+                denied_networks: Vec::new(),
+                trusted_networks: Vec::new(),
+                dnsbl: None,
                 mail_tx,
                 affirm_tx,
                 shutdown_rx,
@@ -80,9 +110,262 @@ impl SmtpServer {
     }
 
     /**
-    Starts the server. Returns an error if server could not start
+    Sets the maximum size of a message (bytes), which is advertised with the SIZE extension.
+    Larger messages are rejected. Defaults to 10 MiB.
+    */
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.config.max_message_size = Some(max_message_size);
+        self
+    }
+
+    /**
+    Enables the AUTH command, validating credentials with the given authenticator.
+    The PLAIN and LOGIN mechanisms are only offered once the connection is encrypted.
+    */
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.config.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /**
+    Enables the SCRAM-SHA-256 and CRAM-MD5 mechanisms of the AUTH command, using the stored
+    credentials of users. These mechanisms are offered on unencrypted connections as well,
+    since the password never crosses the wire. CRAM-MD5 is only offered if the store provides
+    passwords.
+    */
+    pub fn credential_store(mut self, credential_store: impl CredentialStore + 'static) -> Self {
+        self.config.credential_store = Some(Arc::new(credential_store));
+        self
+    }
+
+    /**
+    Enables the OAUTHBEARER and XOAUTH2 mechanisms of the AUTH command, verifying bearer
+    tokens with the given token verifier. These mechanisms are only offered over TLS.
+    */
+    pub fn token_verifier(mut self, token_verifier: impl TokenVerifier + 'static) -> Self {
+        self.config.token_verifier = Some(Arc::new(token_verifier));
+        self
+    }
+
+    /**
+    Requires clients to authenticate before MAIL FROM is accepted.
+    */
+    pub fn require_auth(mut self, require_auth: bool) -> Self {
+        self.config.require_auth = require_auth;
+        self
+    }
+
+    /**
+    Presents the given certificate to clients sending the given server name (SNI), e.g.
+    `mail.example.com` or `*.example.com`. The certificate and key paths passed to `new`
+    are presented to other clients. Cannot be combined with a prebuilt `tls_config`.
+    */
+    pub fn sni_certificate(mut self, name: &str, cert_path: PathBuf, key_path: PathBuf) -> Self {
+        self.config
+            .sni_certificates
+            .insert(name.to_lowercase(), (cert_path, key_path));
+        self
+    }
+
+    /**
+    Uses a prebuilt TLS configuration instead of loading the certificate and key paths,
+    e.g. to customize the protocol versions or to load certificates from a secrets store.
+    The configuration is shared by all connections and can be changed with `replace_tls_config`.
+    Since the server does not build it, starting fails if certificates, client certificate
+    verification or certificate watching are configured as well.
+    */
+    pub fn tls_config(mut self, tls_config: Arc<ServerConfig>) -> Self {
+        self.config.tls_config = Some(SharedTlsConfig::new(tls_config));
+        self.config.prebuilt_tls = true;
+        self
+    }
+
+    /**
+    Uses a PEM encoded certificate chain and private key instead of the certificate and key paths.
+    Returns an error if the certificates or the key are invalid.
+    */
+    pub fn tls_pem(mut self, cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, ServerError> {
+        tls::validate_pem(cert_pem, key_pem)?;
+        self.config.certs_pem = Some((cert_pem.to_vec(), key_pem.to_vec()));
+        Ok(self)
+    }
+
+    /**
+    Requests a certificate from clients during the TLS handshake (mutual TLS), verified
+    with the given CA bundle. The verified certificate is recorded on every `Mail`.
+    Clients without a certificate are still accepted, unless `require_client_certificate` is set.
+    Cannot be combined with a prebuilt `tls_config`.
+    */
+    pub fn client_ca(mut self, ca_path: PathBuf) -> Self {
+        self.config.client_ca_path = Some(ca_path);
+        self
+    }
+
+    /**
+    Refuses the TLS handshake with clients that do not present a certificate verified with
+    the CA bundle set with `client_ca`.
+    */
+    pub fn require_client_certificate(mut self, require_client_certificate: bool) -> Self {
+        self.config.require_client_certificate = require_client_certificate;
+        self
+    }
+
+    /**
+    Considers clients presenting a verified certificate as authenticated, with the subject
+    of the certificate as their identity, e.g. for relays inside a network.
+    */
+    pub fn client_certificate_auth(mut self, client_certificate_auth: bool) -> Self {
+        self.config.client_certificate_auth = client_certificate_auth;
+        self
+    }
+
+    /**
+    Checks the certificate and key paths for changes at the given interval while the server
+    is running, and reloads them when they change, e.g. after a renewal.
+    Cannot be combined with a prebuilt `tls_config`.
+    */
+    pub fn watch_certificates(mut self, interval: Duration) -> Self {
+        self.config.watch_interval = Some(interval);
+        self
+    }
+
+    /**
+    Refuses MAIL, RCPT and AUTH with `530 Must issue a STARTTLS command first` until the
+    connection is encrypted, so that no mail is ever accepted in plaintext.
+    Starting the server fails without a TLS configuration.
+    */
+    pub fn require_tls(mut self, require_tls: bool) -> Self {
+        self.config.require_tls = require_tls;
+        self
+    }
+
+    /**
+    Performs the TLS handshake as soon as a connection is accepted, so that the greeting is
+    already encrypted (RFC 8314), e.g. for submission on port 465. STARTTLS is not offered on
+    such connections. Starting the server fails without a TLS configuration.
+    */
+    pub fn implicit_tls(mut self, implicit_tls: bool) -> Self {
+        self.config.implicit_tls = implicit_tls;
+        self
+    }
+
+    /**
+    Prepends the `Return-Path:` and `Received:` trace headers (RFC 5321, section 4.4) to the
+    data of every mail, recording the sender, the client, the protocol and the queue id.
+    */
+    pub fn trace_headers(mut self, trace_headers: bool) -> Self {
+        self.config.trace_headers = trace_headers;
+        self
+    }
+
+    /**
+    Asks the given handler whether to accept every received mail before the client is
+    answered, so that `250` is only sent once the mail is safely stored.
+    */
+    pub fn message_handler(mut self, message_handler: impl MessageHandler + 'static) -> Self {
+        self.config.message_handler = Some(Arc::new(message_handler));
+        self
+    }
+
+    /**
+    Sets how long the message handler may take to decide on a mail, 5 minutes by default.
+    The client is answered with `451` if it takes longer, and tries again later.
+    */
+    pub fn message_handler_timeout(mut self, message_handler_timeout: Duration) -> Self {
+        self.config.message_handler_timeout = Some(message_handler_timeout);
+        self
+    }
+
+    /**
+    Asks the given policy whether to accept each recipient of RCPT TO, so that unknown users
+    are rejected during the transaction rather than bounced later.
+    */
+    pub fn recipient_policy(mut self, recipient_policy: impl RecipientPolicy + 'static) -> Self {
+        self.config.recipient_policy = Some(Arc::new(recipient_policy));
+        self
+    }
+
+    /**
+    Asks the given policy whether to accept the sender of MAIL FROM, e.g. to block known bad
+    senders or to make authenticated users send as their own addresses.
+    */
+    pub fn sender_policy(mut self, sender_policy: impl SenderPolicy + 'static) -> Self {
+        self.config.sender_policy = Some(Arc::new(sender_policy));
+        self
+    }
+
+    /**
+    Asks the given policy whether to serve every accepted connection before the client is
+    greeted, e.g. with IP reputation or firewall rules. Refused clients are told why and
+    disconnected, and the greeting of suspicious clients can be delayed.
+    */
+    pub fn connection_policy(mut self, connection_policy: impl ConnectionPolicy + 'static) -> Self {
+        self.config.connection_policy = Some(Arc::new(connection_policy));
+        self
+    }
+
+    /**
+    Only serves clients connecting from the given networks, e.g. `192.0.2.0/24` or
+    `2001:db8::/32`. Other clients are refused with `554 No SMTP service here`.
+    */
+    pub fn allowed_networks(mut self, networks: Vec<IpNetwork>) -> Self {
+        self.config.allowed_networks = networks;
+        self
+    }
+
+    /**
+    Refuses clients connecting from the given networks with `554 No SMTP service here`,
+    even if they are in the allowed networks.
+    */
+    pub fn denied_networks(mut self, networks: Vec<IpNetwork>) -> Self {
+        self.config.denied_networks = networks;
+        self
+    }
+
+    /**
+    Trusts the relays connecting from the given networks: they are always served, may send
+    mail without authenticating, and are not checked by the sender and recipient policies.
+    */
+    pub fn trusted_networks(mut self, networks: Vec<IpNetwork>) -> Self {
+        self.config.trusted_networks = networks;
+        self
+    }
+
+    /**
+    Looks up the address of every client on the given DNS blocklists before it is greeted.
+    Clients listed on a rejecting blocklist are refused with `554`, listings on other
+    blocklists are recorded in `Session::dnsbl`. Trusted relays are not looked up.
+    */
+    pub fn dnsbl(mut self, dnsbl: Dnsbl) -> Self {
+        self.config.dnsbl = Some(Arc::new(dnsbl));
+        self
+    }
+
+    /**
+    Starts the server. Returns an error if server could not start, or if the certificates
+    and keys could not be loaded.
     */
-    pub async fn start(self) -> Result<SmtpServer<Listening>, ServerError> {
+    pub async fn start(mut self) -> Result<SmtpServer<Listening>, ServerError> {
+        // A prebuilt configuration would silently ignore the options the server builds one from
+        if self.config.prebuilt_tls {
+            if let Some(option) = tls::prebuilt_conflict(&self.config) {
+                return Err(ServerError::PrebuiltTls(option));
+            }
+        }
+
+        // The certificates are loaded once and the configuration is shared by all connections
+        if self.config.tls_config.is_none() {
+            self.config.tls_config = tls::from_config(&self.config)?.map(SharedTlsConfig::new);
+        }
+        if self.config.tls_config.is_none() {
+            // Either no connection could be served or no mail could ever be accepted
+            if self.config.implicit_tls || self.config.require_tls {
+                log::error!("TLS is required but no certificates or keys were provided.");
+                return Err(ServerError::NoTls);
+            }
+            log::info!("No certificates or keys provided, STARTTLS will not be available.");
+        }
+
         task::spawn(start_server(self.config.clone()));
         log::info!("Requesting server start...");
         self.affirm_rx.recv().await?;
@@ -98,6 +381,31 @@ impl SmtpServer {
 }
 
 impl SmtpServer<Listening> {
+    /**
+    Reloads the certificates and keys from their paths. New handshakes use the new
+    certificates, while established sessions are not affected.
+    Returns an error if the server was started without TLS or the files cannot be loaded.
+    A prebuilt TLS configuration has no files to reload from, use `replace_tls_config` instead.
+    */
+    pub fn reload_tls(&self) -> Result<(), ServerError> {
+        if self.config.prebuilt_tls {
+            return Err(ServerError::PrebuiltTls("reloading certificates"));
+        }
+        let tls_config = tls::from_config(&self.config)?.ok_or(ServerError::NoTls)?;
+        self.replace_tls_config(tls_config)
+    }
+
+    /**
+    Replaces the TLS configuration used by new handshakes, e.g. with certificates loaded
+    from a secrets store. Returns an error if the server was started without TLS.
+    */
+    pub fn replace_tls_config(&self, tls_config: Arc<ServerConfig>) -> Result<(), ServerError> {
+        let shared = self.config.tls_config.as_ref().ok_or(ServerError::NoTls)?;
+        shared.replace(tls_config);
+        log::info!("TLS configuration replaced");
+        Ok(())
+    }
+
     /**
     Stops the server. Returns an error if server could not stop.
     */
@@ -1,13 +1,23 @@
 mod create;
 mod start;
+mod tls;
 
-use std::{marker::PhantomData, path::PathBuf, time::Duration};
+pub use tls::{SharedTlsConfig, SniResolver};
+
+use std::{collections::HashMap, marker::PhantomData, path::PathBuf, sync::Arc, time::Duration};
 
 use async_std::channel::{Receiver, RecvError, SendError, Sender};
+use ipnetwork::IpNetwork;
 use thiserror::Error;
 use tokio::{io, task::JoinError};
+use tokio_rustls::rustls::{self, server::VerifierBuilderError};
 
-use crate::connection::Mail;
+use crate::{
+    auth::{Authenticator, CredentialStore, TokenVerifier},
+    connection::Mail,
+    dnsbl::Dnsbl,
+    hooks::{ConnectionPolicy, MessageHandler, RecipientPolicy, SenderPolicy},
+};
 
 #[derive(Error, Debug)]
 /**
@@ -50,6 +60,32 @@ pub enum ServerError {
      * Occurs when the server cannot confirm a shutdown via signalling
      */
     Shutdown,
+    #[error("Could not load certificates: {0}")]
+    /**
+     * Occurs when the certificates or the private key cannot be read or parsed
+     */
+    Certificate(#[source] io::Error),
+    #[error("Invalid TLS configuration: {0}")]
+    /**
+     * Occurs when the certificates and the private key cannot be used for TLS
+     */
+    Tls(#[from] rustls::Error),
+    #[error("Invalid client CA bundle: {0}")]
+    /**
+     * Occurs when client certificates cannot be verified with the given CA bundle
+     */
+    ClientVerifier(#[from] VerifierBuilderError),
+    #[error("TLS is not configured")]
+    /**
+     * Occurs when the TLS configuration is reloaded on a server started without one, or when
+     * a server requiring TLS or using implicit TLS is started without one
+     */
+    NoTls,
+    #[error("A prebuilt TLS configuration cannot be combined with {0}")]
+    /**
+     * Occurs when a prebuilt TLS configuration is combined with options that build or reload one
+     */
+    PrebuiltTls(&'static str),
     #[error("Server is already running")]
     /**
      * Occurs when the server is already running and a start is attempted
@@ -69,6 +105,35 @@ pub enum ServerError {
    - `buffer_size`: The size of the buffer used for reading incoming data (bytes).
    - `certs_path`: The path to the certificates used for encryption.
    - `key_path`: The path to the keys used for encryption.
+   - `certs_pem`: The PEM encoded certificates and key used for encryption, instead of the paths.
+   - `client_ca_path`: The path to the CA bundle used to verify client certificates (mutual TLS).
+   - `require_client_certificate`: Whether clients without a verified certificate are refused.
+   - `client_certificate_auth`: Whether a verified client certificate counts as authentication.
+   - `sni_certificates`: The certificate and key paths presented to clients sending the given
+     server name (SNI), the certificate and key paths above being the default.
+   - `tls_config`: The TLS configuration shared by all connections, built once from the
+     certificates and keys when the server starts if it was not provided.
+   - `prebuilt_tls`: Whether the TLS configuration was provided instead of built by the server.
+   - `watch_interval`: The interval at which the certificate and key files are checked for changes.
+   - `max_message_size`: The maximum size of a message (bytes), 10 MiB if not set.
+   - `authenticator`: The authenticator used to validate PLAIN and LOGIN credentials.
+   - `credential_store`: The credential store used by the SCRAM-SHA-256 and CRAM-MD5 mechanisms.
+   - `token_verifier`: The token verifier used by the OAUTHBEARER and XOAUTH2 mechanisms.
+   - `require_auth`: Whether clients must authenticate before sending mail.
+   - `require_tls`: Whether MAIL, RCPT and AUTH are refused until STARTTLS has been issued.
+   - `implicit_tls`: Whether connections start with a TLS handshake (SMTPS) instead of offering STARTTLS.
+   - `trace_headers`: Whether the `Return-Path:` and `Received:` headers are prepended to every mail.
+   - `message_handler`: The handler deciding whether a received mail is accepted.
+   - `message_handler_timeout`: The duration after which a mail the handler has not decided on is
+     answered with `451`, 5 minutes if not set.
+   - `recipient_policy`: The policy deciding whether each recipient is accepted.
+   - `sender_policy`: The policy deciding whether the sender of MAIL FROM is accepted.
+   - `connection_policy`: The policy deciding whether a client is served, before it is greeted.
+   - `allowed_networks`: The networks clients must connect from, any network if empty.
+   - `denied_networks`: The networks from which clients are refused.
+   - `trusted_networks`: The networks of trusted relays, which are always served and bypass the
+     authentication requirement and the sender and recipient policies.
+   - `dnsbl`: The DNS blocklists the address of clients is looked up on, shared by all connections.
    - `mail_tx`: The sender for the mail channel.
    - `affirm_tx`: The sender for the affirmation channel.
    - `shutdown_rx`: The receiver for the shutdown channel.
@@ -81,6 +146,31 @@ pub struct Config {
     pub buffer_size: Option<usize>,
     pub certs_path: Option<PathBuf>,
     pub key_path: Option<PathBuf>,
+    pub certs_pem: Option<(Vec<u8>, Vec<u8>)>,
+    pub client_ca_path: Option<PathBuf>,
+    pub require_client_certificate: bool,
+    pub client_certificate_auth: bool,
+    pub sni_certificates: HashMap<String, (PathBuf, PathBuf)>,
+    pub tls_config: Option<SharedTlsConfig>,
+    pub prebuilt_tls: bool,
+    pub watch_interval: Option<Duration>,
+    pub max_message_size: Option<usize>,
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub credential_store: Option<Arc<dyn CredentialStore>>,
+    pub token_verifier: Option<Arc<dyn TokenVerifier>>,
+    pub require_auth: bool,
+    pub require_tls: bool,
+    pub implicit_tls: bool,
+    pub trace_headers: bool,
+    pub message_handler: Option<Arc<dyn MessageHandler>>,
+    pub message_handler_timeout: Option<Duration>,
+    pub recipient_policy: Option<Arc<dyn RecipientPolicy>>,
+    pub sender_policy: Option<Arc<dyn SenderPolicy>>,
+    pub connection_policy: Option<Arc<dyn ConnectionPolicy>>,
+    pub allowed_networks: Vec<IpNetwork>,
+    pub denied_networks: Vec<IpNetwork>,
+    pub trusted_networks: Vec<IpNetwork>,
+    pub dnsbl: Option<Arc<Dnsbl>>,
     pub mail_tx: Sender<Mail>,
     pub affirm_tx: Sender<()>,
     pub shutdown_rx: Receiver<()>,
@@ -122,9 +212,9 @@ pub struct Closed;
     #[tokio::main]
     async fn main() {
         let server = SmtpServer::new(
-            "localhost",
+            "localhost".to_string(),
             2525,
-            "localhost",
+            "localhost".to_string(),
             Some(Duration::from_secs(10)),
             None,
             None,
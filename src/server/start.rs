@@ -1,11 +1,9 @@
-use std::time::Duration;
-
 use futures::{select, FutureExt};
 use tokio::net::TcpListener;
 
 use crate::connection::{Connection, Stream};
 
-use super::{Config, ServerError};
+use super::{tls::watch, Config, ServerError};
 
 /**
    Waits for a connection to be established and then processes it.
@@ -16,31 +14,11 @@ async fn wait_for_connection(listener: &TcpListener, config: Config) {
             log::info!("New connection: {}", addr);
             tokio::spawn(async move {
                 // Create a new connection instance
-                let connection = Connection::new(
-                    config.domain,
-                    Stream::Plain(socket),
-                    config.certs_path.clone(),
-                    config.key_path.clone(),
-                    config.buffer_size,
-                    config.timeout.unwrap_or(Duration::from_secs(10)),
-                )
-                .await;
-
-                // Process the connection
-                let process = connection.process().await;
+                let connection = Connection::new(Stream::Plain(socket), &config).await;
 
-                match process {
-                    Ok(value) => {
-                        // The final result should be an email that we can forward to the channel
-                        if let Ok(()) = config.mail_tx.send(value).await {
-                            log::info!("Mail forwarded to channel");
-                        } else {
-                            log::error!("Error sending mail to to channel");
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("Processing error: {}", e);
-                    }
+                // Process the connection, every received mail is forwarded to the channel
+                if let Err(e) = connection.process().await {
+                    log::error!("Processing error: {}", e);
                 }
             });
         }
@@ -64,11 +42,24 @@ pub async fn start_server(config: Config) -> Result<(), ServerError> {
         })?;
     config.affirm_tx.send(()).await?;
 
+    // Renewed certificates are picked up without restarting the server
+    let watcher = match (&config.tls_config, config.watch_interval) {
+        (Some(tls_config), Some(interval)) => Some(tokio::spawn(watch(
+            tls_config.clone(),
+            config.clone(),
+            interval,
+        ))),
+        _ => None,
+    };
+
     // While listening for incoming connections, we also listen for shutdown signals.
     loop {
         select! {
             _ = config.shutdown_rx.recv().fuse() => {
                 log::info!("Shutting down server");
+                if let Some(watcher) = &watcher {
+                    watcher.abort();
+                }
                 if let Err(error)=config.affirm_tx.send(()).await {
                     log::error!("Error sending shutdown confirmation: {}", error);
                 }
@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls_pemfile::{certs, private_key};
+use tokio::{io, time::interval};
+use tokio_rustls::rustls::{
+    crypto::aws_lc_rs::sign::any_supported_type,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WantsServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    ConfigBuilder, RootCertStore, ServerConfig,
+};
+
+use super::{Config, ServerError};
+
+#[derive(Debug, Clone)]
+/**
+## Shared TLS configuration
+   The `SharedTlsConfig` struct holds the TLS configuration shared by the server and all of
+   its connections. It can be replaced at runtime, e.g. when certificates are renewed:
+   new handshakes use the new configuration, while established sessions are not affected.
+*/
+pub struct SharedTlsConfig(Arc<RwLock<Arc<ServerConfig>>>);
+
+impl SharedTlsConfig {
+    /**
+       Shares the given configuration.
+    */
+    pub fn new(tls_config: Arc<ServerConfig>) -> Self {
+        SharedTlsConfig(Arc::new(RwLock::new(tls_config)))
+    }
+
+    /**
+       The configuration to use for a new handshake.
+    */
+    pub fn current(&self) -> Arc<ServerConfig> {
+        // The lock is never held across a panic, but a poisoned lock still holds a valid configuration
+        self.0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /**
+       Replaces the configuration used by new handshakes.
+    */
+    pub fn replace(&self, tls_config: Arc<ServerConfig>) {
+        *self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = tls_config;
+    }
+}
+
+#[derive(Debug, Default)]
+/**
+## SNI certificate resolver
+   The `SniResolver` struct selects the certificate presented to a client by the server name
+   it sent (SNI), e.g. when hosting mail for several domains on one address.
+   Names can be exact, like `mail.example.com`, or wildcards, like `*.example.com`.
+   Clients sending no or an unknown name are presented the default certificate, if any.
+
+   It is built from the certificates set with `SmtpServer::sni_certificate`, but can also be
+   used to build a `rustls::ServerConfig` from certificates kept in memory.
+*/
+pub struct SniResolver {
+    certificates: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    /**
+       Creates a resolver without any certificate.
+    */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+       Adds the PEM encoded certificate chain and private key presented for the given name.
+    */
+    pub fn add_pem(
+        &mut self,
+        name: &str,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<(), ServerError> {
+        self.certificates
+            .insert(name.to_lowercase(), certified_key(cert_pem, key_pem)?);
+        Ok(())
+    }
+
+    /**
+       Sets the PEM encoded certificate chain and private key presented when no name matches.
+    */
+    pub fn set_default_pem(&mut self, cert_pem: &[u8], key_pem: &[u8]) -> Result<(), ServerError> {
+        self.default = Some(certified_key(cert_pem, key_pem)?);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name().map(str::to_lowercase) else {
+            return self.default.clone();
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        self.certificates
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.certificates.get(&wildcard)))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/**
+   Parses a PEM encoded certificate chain and private key.
+*/
+fn parse_pem(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), ServerError> {
+    let certs = certs(&mut &cert_pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ServerError::Certificate)?;
+    if certs.is_empty() {
+        return Err(ServerError::Certificate(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No certificate found",
+        )));
+    }
+    let key = private_key(&mut &key_pem[..])
+        .map_err(ServerError::Certificate)?
+        .ok_or(ServerError::Certificate(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No private key found",
+        )))?;
+    Ok((certs, key))
+}
+
+/**
+   Parses a PEM encoded certificate chain and private key into a key usable for signing.
+*/
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<Arc<CertifiedKey>, ServerError> {
+    let (certs, key) = parse_pem(cert_pem, key_pem)?;
+    Ok(Arc::new(CertifiedKey::new(
+        certs,
+        any_supported_type(&key)?,
+    )))
+}
+
+/**
+   Checks that a PEM encoded certificate chain and private key can be used.
+*/
+pub fn validate_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<(), ServerError> {
+    certified_key(cert_pem, key_pem).map(|_| ())
+}
+
+/**
+   Starts building the TLS configuration, verifying client certificates with the CA bundle
+   of the server configuration if one is set.
+*/
+fn builder(config: &Config) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, ServerError> {
+    let Some(ca_path) = &config.client_ca_path else {
+        return Ok(ServerConfig::builder().with_no_client_auth());
+    };
+    let ca_pem = std::fs::read(ca_path).map_err(ServerError::Certificate)?;
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut &ca_pem[..]) {
+        roots.add(cert.map_err(ServerError::Certificate)?)?;
+    }
+
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+    // Clients without a certificate are still accepted unless one is required
+    let verifier = match config.require_client_certificate {
+        true => verifier.build()?,
+        false => verifier.allow_unauthenticated().build()?,
+    };
+    Ok(ServerConfig::builder().with_client_cert_verifier(verifier))
+}
+
+/**
+   Reads a certificate chain and private key file.
+*/
+fn read_files(cert_path: &Path, key_path: &Path) -> Result<(Vec<u8>, Vec<u8>), ServerError> {
+    let cert_pem = std::fs::read(cert_path).map_err(ServerError::Certificate)?;
+    let key_pem = std::fs::read(key_path).map_err(ServerError::Certificate)?;
+    Ok((cert_pem, key_pem))
+}
+
+/**
+   Builds the TLS configuration from the certificates and keys of the server configuration.
+   With SNI certificates, the certificate is selected by the name sent by the client and
+   the certificate and key paths are the default. Returns `None` if no certificate is set.
+*/
+pub fn from_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, ServerError> {
+    let default = match (&config.certs_pem, &config.certs_path, &config.key_path) {
+        (Some(pem), _, _) => Some(pem.clone()),
+        (None, Some(cert_path), Some(key_path)) => Some(read_files(cert_path, key_path)?),
+        _ => None,
+    };
+    if config.sni_certificates.is_empty() {
+        let Some((cert_pem, key_pem)) = default else {
+            return Ok(None);
+        };
+        let (certs, key) = parse_pem(&cert_pem, &key_pem)?;
+        let config = builder(config)?.with_single_cert(certs, key)?;
+        return Ok(Some(Arc::new(config)));
+    }
+
+    let mut resolver = SniResolver::new();
+    for (name, (cert_path, key_path)) in &config.sni_certificates {
+        let (cert_pem, key_pem) = read_files(cert_path, key_path)?;
+        resolver.add_pem(name, &cert_pem, &key_pem)?;
+    }
+    if let Some((cert_pem, key_pem)) = default {
+        resolver.set_default_pem(&cert_pem, &key_pem)?;
+    }
+    let config = builder(config)?.with_cert_resolver(Arc::new(resolver));
+    Ok(Some(Arc::new(config)))
+}
+
+/**
+   The option a prebuilt TLS configuration cannot be combined with, if any, since the
+   server would build or reload a configuration from it.
+*/
+pub fn prebuilt_conflict(config: &Config) -> Option<&'static str> {
+    [
+        (
+            config.certs_path.is_some() || config.key_path.is_some(),
+            "certificate and key paths",
+        ),
+        (config.certs_pem.is_some(), "PEM certificates"),
+        (!config.sni_certificates.is_empty(), "SNI certificates"),
+        (
+            config.client_ca_path.is_some() || config.require_client_certificate,
+            "client certificate verification",
+        ),
+        (config.watch_interval.is_some(), "certificate watching"),
+    ]
+    .into_iter()
+    .find_map(|(configured, option)| configured.then_some(option))
+}
+
+/**
+   The certificate, key and CA files of the server configuration.
+*/
+fn files(config: &Config) -> Vec<PathBuf> {
+    let default = [
+        config.certs_path.clone(),
+        config.key_path.clone(),
+        config.client_ca_path.clone(),
+    ];
+    let sni = config
+        .sni_certificates
+        .values()
+        .flat_map(|(cert_path, key_path)| [cert_path.clone(), key_path.clone()]);
+    default.into_iter().flatten().chain(sni).collect()
+}
+
+/**
+   The modification times of the certificate and key files, used to detect a renewal.
+*/
+fn modified(files: &[PathBuf]) -> Option<Vec<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/**
+   Checks the certificate and key files at every interval and reloads them when they change.
+   If the new files cannot be loaded, the current configuration is kept.
+*/
+pub async fn watch(tls_config: SharedTlsConfig, config: Config, period: Duration) {
+    let files = files(&config);
+    let mut ticker = interval(period);
+    let mut last_modified = modified(&files);
+    loop {
+        ticker.tick().await;
+        let current = modified(&files);
+        if current.is_none() || current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        match from_config(&config) {
+            Ok(Some(new_config)) => {
+                tls_config.replace(new_config);
+                log::info!("Certificates reloaded");
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Could not reload certificates: {}", e),
+        }
+    }
+}